@@ -1,16 +1,84 @@
 use std::fmt;
 
-#[derive(Clone, Debug)]
+use num_bigint::BigInt;
+
+use crate::error::FormulaError;
+use crate::tree::Expression;
+
+#[derive(Clone, Debug, Default, PartialEq)]
 pub enum CellValue {
+    #[default]
+    Empty,
     String(String),
     Int(i64),
+    BigInt(BigInt), /* under OverflowPolicy::BigInt, past the i64 range */
     Bool(bool),
     Float(f64),
+    Error(ErrorKind),
+}
+
+/* spreads through evaluation like #DIV/0! and friends in other spreadsheets */
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+    DivideByZero,
+    TypeMismatch(String),
+    BadReference(String),
+    Parse(String),
+    Name(String),               /* unknown function */
+    Arity(String),              /* wrong number of arguments */
+    Cycle(Vec<(usize, usize)>), /* (row, col) path, first and last are the same cell */
+    Overflow(String),           /* integer result out of range under OverflowPolicy::Error */
+}
+
+impl ErrorKind {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::DivideByZero => "#DIV/0!",
+            ErrorKind::TypeMismatch(_) => "#VALUE!",
+            ErrorKind::BadReference(_) => "#REF!",
+            ErrorKind::Parse(_) => "#PARSE!",
+            ErrorKind::Name(_) => "#NAME?",
+            ErrorKind::Arity(_) => "#ARGS!",
+            ErrorKind::Cycle(_) => "#CYCLE!",
+            ErrorKind::Overflow(_) => "#NUM!",
+        }
+    }
+
+    /* the error a code written into formula text stands for, #CYCLE! has no path to give */
+    pub fn from_code(code: &str) -> Option<ErrorKind> {
+        match code {
+            "#DIV/0!" => Some(ErrorKind::DivideByZero),
+            "#VALUE!" => Some(ErrorKind::TypeMismatch("Invalid value".to_string())),
+            "#REF!" => Some(ErrorKind::BadReference(
+                "Invalid cell reference".to_string(),
+            )),
+            "#PARSE!" => Some(ErrorKind::Parse("Invalid formula".to_string())),
+            "#NAME?" => Some(ErrorKind::Name("Unknown function".to_string())),
+            "#ARGS!" => Some(ErrorKind::Arity("Wrong number of arguments".to_string())),
+            "#NUM!" => Some(ErrorKind::Overflow("Number out of range".to_string())),
+            _ => None,
+        }
+    }
 }
 
-impl Default for CellValue {
-    fn default() -> Self {
-        CellValue::Int(0)
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::DivideByZero => write!(f, "Divide by zero error"),
+            ErrorKind::TypeMismatch(message)
+            | ErrorKind::BadReference(message)
+            | ErrorKind::Parse(message)
+            | ErrorKind::Name(message)
+            | ErrorKind::Arity(message)
+            | ErrorKind::Overflow(message) => write!(f, "{}", message),
+            ErrorKind::Cycle(path) => {
+                let path: Vec<String> = path
+                    .iter()
+                    .map(|(row, col)| format!("#[{}, {}]", col, row))
+                    .collect();
+                write!(f, "Circular reference: {}", path.join(" -> "))
+            }
+        }
     }
 }
 
@@ -18,13 +86,17 @@ impl Default for CellValue {
 pub struct Cell {
     value: CellValue, /* resolved from formula */
     formula: Option<String>,
+    ast: Option<Expression>,          /* cached parse of formula */
+    diagnostic: Option<FormulaError>, /* where the formula failed, if it did */
 }
 
 impl Cell {
     pub fn new_empty() -> Self {
         Cell {
-            value: CellValue::Int(0),
+            value: CellValue::Empty,
             formula: None,
+            ast: None,
+            diagnostic: None,
         }
     }
 
@@ -32,15 +104,40 @@ impl Cell {
         Cell {
             value,
             formula: None,
+            ast: None,
+            diagnostic: None,
         }
     }
 
     pub fn set_value(&mut self, value: CellValue) {
         self.value = value;
+        self.diagnostic = None;
     }
 
     pub fn set_formula(&mut self, formula: String) {
         self.formula = Some(formula);
+        self.ast = None;
+        self.diagnostic = None;
+    }
+
+    pub fn clear_formula(&mut self) {
+        self.formula = None;
+        self.ast = None;
+        self.diagnostic = None;
+    }
+
+    pub fn set_ast(&mut self, ast: Expression) {
+        self.ast = Some(ast);
+    }
+
+    pub fn set_error(&mut self, error: ErrorKind) {
+        self.value = CellValue::Error(error);
+        self.diagnostic = None;
+    }
+
+    pub fn set_failure(&mut self, failure: FormulaError) {
+        self.value = CellValue::Error(failure.kind());
+        self.diagnostic = Some(failure);
     }
 
     pub fn get_value(&self) -> &CellValue {
@@ -51,12 +148,36 @@ impl Cell {
         self.formula.as_ref()
     }
 
+    pub fn get_ast(&self) -> Option<&Expression> {
+        self.ast.as_ref()
+    }
+
+    pub fn get_error(&self) -> Option<&ErrorKind> {
+        match &self.value {
+            CellValue::Error(kind) => Some(kind),
+            _ => None,
+        }
+    }
+
+    pub fn get_diagnostic(&self) -> Option<&FormulaError> {
+        self.diagnostic.as_ref()
+    }
+
+    /* the formula with a caret under the part that failed */
+    pub fn render_diagnostic(&self) -> Option<String> {
+        let formula = self.formula.as_deref().unwrap_or_default();
+        self.diagnostic.as_ref().map(|d| d.render(formula))
+    }
+
     pub fn evaluate(&self) -> String {
         match &self.value {
+            CellValue::Empty => String::new(),
             CellValue::String(s) => s.clone(),
             CellValue::Int(i) => i.to_string(),
+            CellValue::BigInt(i) => i.to_string(),
             CellValue::Bool(b) => b.to_string(),
             CellValue::Float(f) => f.to_string(),
+            CellValue::Error(kind) => kind.code().to_string(),
         }
     }
 }
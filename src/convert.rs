@@ -0,0 +1,142 @@
+use num_bigint::BigInt;
+use num_traits::{FromPrimitive, Zero};
+
+use crate::error::EvalError;
+use crate::number::{self, OverflowPolicy};
+use crate::tree::{mismatch, Expression};
+
+/* how a Float loses its fraction on the way to an Integer */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Rounding {
+    #[default]
+    Truncate, /* toward zero, what int does */
+    Floor,
+    Ceiling,
+    HalfAwayFromZero, /* 2.5 -> 3, -2.5 -> -3 */
+    HalfEven,         /* 2.5 -> 2, 3.5 -> 4 */
+}
+
+impl Rounding {
+    pub fn apply(&self, value: f64) -> f64 {
+        match self {
+            Rounding::Truncate => value.trunc(),
+            Rounding::Floor => value.floor(),
+            Rounding::Ceiling => value.ceil(),
+            Rounding::HalfAwayFromZero => value.round(),
+            Rounding::HalfEven => value.round_ties_even(),
+        }
+    }
+}
+
+/*
+ * true is 1 and false 0, strings are parsed. a float out of the i64 range
+ * follows the overflow policy like any other integer result.
+ */
+pub fn to_int(
+    value: &Expression,
+    rounding: Rounding,
+    policy: OverflowPolicy,
+) -> Result<Expression, EvalError> {
+    match value {
+        Expression::Empty => Ok(Expression::Integer(0)),
+        Expression::Integer(_) | Expression::BigInteger(_) => Ok(value.clone()),
+        Expression::Boolean(b) => Ok(Expression::Integer(*b as i64)),
+        Expression::Float(f) => float_to_int(*f, rounding, policy),
+        Expression::String(text) => match parse_number(text)? {
+            Expression::Float(f) => match text.trim().parse::<BigInt>() {
+                /* digits past the i64 range, exact under the BigInt policy */
+                Ok(integer) => {
+                    number::overflowed(policy, "integer conversion", f, || Some(integer))
+                }
+                Err(_) => float_to_int(f, rounding, policy),
+            },
+            integer => Ok(integer),
+        },
+        _ => Err(mismatch("Cannot convert to an integer")),
+    }
+}
+
+fn float_to_int(
+    value: f64,
+    rounding: Rounding,
+    policy: OverflowPolicy,
+) -> Result<Expression, EvalError> {
+    if !value.is_finite() {
+        return Err(number::overflow("integer conversion"));
+    }
+    let rounded = rounding.apply(value);
+    /* every float past 2^63 is a whole number already, the cast is exact below it */
+    if rounded >= -(2f64.powi(63)) && rounded < 2f64.powi(63) {
+        return Ok(Expression::Integer(rounded as i64));
+    }
+    number::overflowed(policy, "integer conversion", rounded, || {
+        BigInt::from_f64(rounded)
+    })
+}
+
+pub fn to_float(value: &Expression) -> Result<Expression, EvalError> {
+    match value {
+        Expression::Empty => Ok(Expression::Float(0.0)),
+        Expression::Boolean(b) => Ok(Expression::Float(*b as i64 as f64)),
+        Expression::String(text) => match parse_number(text)? {
+            Expression::Integer(i) => Ok(Expression::Float(i as f64)),
+            float => Ok(float),
+        },
+        _ => match number::to_float(value) {
+            Some(f) => Ok(Expression::Float(f)),
+            None => Err(mismatch("Cannot convert to a float")),
+        },
+    }
+}
+
+/* numbers are true unless zero, strings have to say true or false */
+pub fn to_bool(value: &Expression) -> Result<Expression, EvalError> {
+    match value {
+        Expression::Empty => Ok(Expression::Boolean(false)),
+        Expression::Boolean(_) => Ok(value.clone()),
+        Expression::Integer(i) => Ok(Expression::Boolean(*i != 0)),
+        Expression::BigInteger(i) => Ok(Expression::Boolean(!i.is_zero())),
+        Expression::Float(f) => Ok(Expression::Boolean(*f != 0.0)),
+        Expression::String(text) => match text.trim().to_ascii_lowercase().as_str() {
+            "true" => Ok(Expression::Boolean(true)),
+            "false" => Ok(Expression::Boolean(false)),
+            _ => Err(mismatch(&format!(
+                "Cannot convert \"{}\" to a boolean",
+                text
+            ))),
+        },
+        _ => Err(mismatch("Cannot convert to a boolean")),
+    }
+}
+
+pub fn to_string(value: &Expression) -> Result<Expression, EvalError> {
+    Ok(Expression::String(text(value)?))
+}
+
+/* the text a cell would show for the value */
+pub(crate) fn text(value: &Expression) -> Result<String, EvalError> {
+    match value {
+        Expression::Empty => Ok(String::new()),
+        Expression::String(text) => Ok(text.clone()),
+        Expression::Integer(i) => Ok(i.to_string()),
+        Expression::BigInteger(i) => Ok(i.to_string()),
+        Expression::Float(f) => Ok(f.to_string()),
+        Expression::Boolean(b) => Ok(b.to_string()),
+        _ => Err(mismatch("Cannot convert to a string")),
+    }
+}
+
+/* an Integer if the text is one, otherwise a finite Float */
+fn parse_number(text: &str) -> Result<Expression, EvalError> {
+    let trimmed = text.trim();
+    if let Ok(integer) = trimmed.parse::<i64>() {
+        return Ok(Expression::Integer(integer));
+    }
+    match trimmed.parse::<f64>() {
+        Ok(float) if float.is_finite() => Ok(Expression::Float(float)),
+        _ => Err(mismatch(&format!(
+            "Cannot convert \"{}\" to a number",
+            text
+        ))),
+    }
+}
@@ -0,0 +1,224 @@
+use std::fmt;
+
+use crate::cell::ErrorKind;
+
+/* byte offsets into the formula text, end is exclusive */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LexError {
+    UnexpectedCharacter { found: char, span: Span },
+    UnterminatedString { span: Span }, /* from the opening quote to the end */
+    InvalidEscape { span: Span },
+    InvalidNumber { text: String, span: Span },
+}
+
+impl LexError {
+    pub fn span(&self) -> Span {
+        match self {
+            LexError::UnexpectedCharacter { span, .. }
+            | LexError::UnterminatedString { span }
+            | LexError::InvalidEscape { span }
+            | LexError::InvalidNumber { span, .. } => *span,
+        }
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LexError::UnexpectedCharacter { found, .. } => {
+                write!(f, "Unexpected character '{}'", found)
+            }
+            LexError::UnterminatedString { span } => {
+                write!(f, "Unterminated string starting at {}", span.start)
+            }
+            LexError::InvalidEscape { .. } => write!(f, "Invalid escape sequence in string"),
+            LexError::InvalidNumber { text, .. } => write!(f, "Malformed number '{}'", text),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseError {
+    UnexpectedToken {
+        found: String,
+        span: Span,
+    },
+    Expected {
+        expected: &'static str,
+        found: String,
+        span: Span,
+    },
+    UnexpectedEnd {
+        expected: &'static str,
+        span: Span,
+    },
+    OutOfRange {
+        found: String,
+        span: Span,
+    },
+}
+
+impl ParseError {
+    pub fn span(&self) -> Span {
+        match self {
+            ParseError::UnexpectedToken { span, .. }
+            | ParseError::Expected { span, .. }
+            | ParseError::UnexpectedEnd { span, .. }
+            | ParseError::OutOfRange { span, .. } => *span,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedToken { found, .. } => write!(f, "Unexpected token '{}'", found),
+            ParseError::Expected {
+                expected, found, ..
+            } => write!(f, "Expected {}, found '{}'", expected, found),
+            ParseError::UnexpectedEnd { expected, .. } => {
+                write!(f, "Expected {}, found end of formula", expected)
+            }
+            ParseError::OutOfRange { found, .. } => {
+                write!(f, "Number '{}' is out of range", found)
+            }
+        }
+    }
+}
+
+/* span is the innermost node that failed, None for errors with no source */
+#[derive(Clone, Debug, PartialEq)]
+pub struct EvalError {
+    pub kind: ErrorKind,
+    pub span: Option<Span>,
+}
+
+impl EvalError {
+    pub fn or_span(mut self, span: Span) -> Self {
+        self.span.get_or_insert(span);
+        self
+    }
+}
+
+impl From<ErrorKind> for EvalError {
+    fn from(kind: ErrorKind) -> Self {
+        EvalError { kind, span: None }
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FormulaError {
+    Lex(LexError),
+    Parse(ParseError),
+    Eval(EvalError),
+}
+
+impl FormulaError {
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            FormulaError::Lex(e) => Some(e.span()),
+            FormulaError::Parse(e) => Some(e.span()),
+            FormulaError::Eval(e) => e.span,
+        }
+    }
+
+    /* the value the cell shows for this failure */
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            FormulaError::Lex(e) => ErrorKind::Parse(e.to_string()),
+            FormulaError::Parse(e) => ErrorKind::Parse(e.to_string()),
+            FormulaError::Eval(e) => e.kind.clone(),
+        }
+    }
+
+    /*
+     * the formula, a caret line under the failing span, then the message:
+     *
+     *   1 + "a"
+     *   ^^^^^^^
+     *   Incompatible types for addition
+     */
+    pub fn render(&self, source: &str) -> String {
+        let span = match self.span() {
+            Some(span) => span,
+            None => return format!("{}\n{}", source, self),
+        };
+        let start = span.start.min(source.len());
+        let end = span.end.clamp(start, source.len());
+        let column = source[..start].chars().count();
+        let width = source[start..end].chars().count().max(1);
+        format!(
+            "{}\n{}{}\n{}",
+            source,
+            " ".repeat(column),
+            "^".repeat(width),
+            self
+        )
+    }
+}
+
+impl fmt::Display for FormulaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FormulaError::Lex(e) => write!(f, "{}", e),
+            FormulaError::Parse(e) => write!(f, "{}", e),
+            FormulaError::Eval(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<LexError> for FormulaError {
+    fn from(e: LexError) -> Self {
+        FormulaError::Lex(e)
+    }
+}
+
+impl From<ParseError> for FormulaError {
+    fn from(e: ParseError) -> Self {
+        FormulaError::Parse(e)
+    }
+}
+
+impl From<EvalError> for FormulaError {
+    fn from(e: EvalError) -> Self {
+        FormulaError::Eval(e)
+    }
+}
+
+/* a failing script line, numbered from 1. spans point into that line */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScriptError {
+    pub line: usize,
+    pub error: FormulaError,
+}
+
+impl ScriptError {
+    pub fn render(&self, script: &str) -> String {
+        let source = script.lines().nth(self.line - 1).unwrap_or_default();
+        format!("line {}:\n{}", self.line, self.error.render(source))
+    }
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.error)
+    }
+}
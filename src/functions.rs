@@ -0,0 +1,294 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::cell::{CellValue, ErrorKind};
+use crate::convert::{self, Rounding};
+use crate::error::EvalError;
+use crate::graph::CellPos;
+use crate::number::{self, Operands, OverflowPolicy};
+use crate::tree::{mismatch, Expression};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arity {
+    Exact(usize),
+    AtLeast(usize),
+    Between(usize, usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, count: usize) -> bool {
+        match *self {
+            Arity::Exact(n) => count == n,
+            Arity::AtLeast(n) => count >= n,
+            Arity::Between(min, max) => (min..=max).contains(&count),
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Arity::Exact(n) => write!(f, "{}", n),
+            Arity::AtLeast(n) => write!(f, "at least {}", n),
+            Arity::Between(min, max) => write!(f, "{} to {}", min, max),
+        }
+    }
+}
+
+/* takes the already evaluated arguments, blank cells included, and the grid's overflow policy */
+pub type Builtin = fn(&[Expression], OverflowPolicy) -> Result<Expression, EvalError>;
+
+/*
+ * a function supplied by the embedding application, callable from formula
+ * text once registered with Grid::register_function. impure functions
+ * (clocks, random numbers, lookups into outside state) make every cell that
+ * calls them recalculate on each change to the grid.
+ */
+pub trait UserFunction {
+    fn name(&self) -> &str;
+    fn arity(&self) -> Arity;
+    fn is_pure(&self) -> bool {
+        true
+    }
+    fn call(&self, args: &[Argument]) -> Result<CellValue, ErrorKind>;
+}
+
+/* an evaluated argument as a user function sees it */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Argument {
+    Value(CellValue),
+    /* corners and the occupied cells, blank ones are left out */
+    Range {
+        start: CellPos,
+        end: CellPos,
+        cells: Vec<(CellPos, CellValue)>,
+    },
+}
+
+impl Argument {
+    fn from_expression(expr: &Expression) -> Result<Argument, ErrorKind> {
+        match expr {
+            Expression::Array(range) => {
+                let cells = range
+                    .cells
+                    .iter()
+                    .map(|(pos, value)| Ok((*pos, value.to_cell_value()?)))
+                    .collect::<Result<Vec<(CellPos, CellValue)>, ErrorKind>>()?;
+                Ok(Argument::Range {
+                    start: range.start,
+                    end: range.end,
+                    cells,
+                })
+            }
+            _ => Ok(Argument::Value(expr.to_cell_value()?)),
+        }
+    }
+
+    /* a single value, or the occupied cells of a range row by row */
+    pub fn values(&self) -> Vec<&CellValue> {
+        match self {
+            Argument::Value(value) => vec![value],
+            Argument::Range { cells, .. } => cells.iter().map(|(_, value)| value).collect(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Function {
+    Builtin(Arity, Builtin),
+    User(Rc<dyn UserFunction>),
+}
+
+impl Function {
+    pub fn arity(&self) -> Arity {
+        match self {
+            Function::Builtin(arity, _) => *arity,
+            Function::User(function) => function.arity(),
+        }
+    }
+
+    pub fn is_pure(&self) -> bool {
+        match self {
+            Function::Builtin(_, _) => true,
+            Function::User(function) => function.is_pure(),
+        }
+    }
+
+    pub fn call(
+        &self,
+        args: &[Expression],
+        policy: OverflowPolicy,
+    ) -> Result<Expression, EvalError> {
+        match self {
+            Function::Builtin(_, body) => body(args, policy),
+            Function::User(function) => {
+                let args = args
+                    .iter()
+                    .map(Argument::from_expression)
+                    .collect::<Result<Vec<Argument>, ErrorKind>>()?;
+                let result = function.call(&args)?;
+                Ok(Expression::from_cell_value(&result)?)
+            }
+        }
+    }
+}
+
+/* names are case-insensitive, like every other spreadsheet */
+#[derive(Clone, Default)]
+pub struct FunctionRegistry {
+    functions: HashMap<String, Function>,
+}
+
+impl FunctionRegistry {
+    pub fn new() -> Self {
+        FunctionRegistry::default()
+    }
+
+    pub fn with_builtins() -> Self {
+        let mut registry = FunctionRegistry::new();
+        registry.register("max", Arity::AtLeast(1), |values, _| max(values));
+        registry.register("min", Arity::AtLeast(1), |values, _| min(values));
+        registry.register("avg", Arity::AtLeast(1), |values, _| mean(values));
+        registry.register("sum", Arity::AtLeast(1), sum);
+        /* int and float are operators, these cover the other conversions */
+        registry.register("trunc", Arity::Exact(1), |values, policy| {
+            convert::to_int(&values[0], Rounding::Truncate, policy)
+        });
+        registry.register("floor", Arity::Exact(1), |values, policy| {
+            convert::to_int(&values[0], Rounding::Floor, policy)
+        });
+        registry.register("ceil", Arity::Exact(1), |values, policy| {
+            convert::to_int(&values[0], Rounding::Ceiling, policy)
+        });
+        registry.register("round", Arity::Exact(1), |values, policy| {
+            convert::to_int(&values[0], Rounding::HalfAwayFromZero, policy)
+        });
+        registry.register("round_even", Arity::Exact(1), |values, policy| {
+            convert::to_int(&values[0], Rounding::HalfEven, policy)
+        });
+        registry.register("bool", Arity::Exact(1), |values, _| {
+            convert::to_bool(&values[0])
+        });
+        registry.register("string", Arity::Exact(1), |values, _| {
+            convert::to_string(&values[0])
+        });
+        /* lower(a) < lower(b) orders text ignoring case */
+        registry.register("lower", Arity::Exact(1), |values, _| {
+            Ok(Expression::String(
+                convert::text(&values[0])?.to_lowercase(),
+            ))
+        });
+        registry.register("upper", Arity::Exact(1), |values, _| {
+            Ok(Expression::String(
+                convert::text(&values[0])?.to_uppercase(),
+            ))
+        });
+        registry
+    }
+
+    pub fn register(&mut self, name: &str, arity: Arity, body: Builtin) {
+        self.functions
+            .insert(name.to_lowercase(), Function::Builtin(arity, body));
+    }
+
+    /* replaces a builtin or earlier function of the same name */
+    pub fn register_user(&mut self, function: Rc<dyn UserFunction>) {
+        self.functions
+            .insert(function.name().to_lowercase(), Function::User(function));
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<Function> {
+        self.functions.get(&name.to_lowercase()).cloned()
+    }
+
+    /* looks the name up and checks the argument count */
+    pub fn resolve(&self, name: &str, count: usize) -> Result<Function, EvalError> {
+        let function = self
+            .lookup(name)
+            .ok_or_else(|| ErrorKind::Name(format!("Unknown function '{}'", name)))?;
+        if !function.arity().accepts(count) {
+            return Err(ErrorKind::Arity(format!(
+                "{} takes {} arguments, got {}",
+                name,
+                function.arity(),
+                count
+            ))
+            .into());
+        }
+        Ok(function)
+    }
+}
+
+/* ranges are flattened and blank cells skipped rather than counted */
+fn non_empty(values: &[Expression]) -> Vec<&Expression> {
+    let mut found = Vec::new();
+    for value in values {
+        match value {
+            Expression::Empty => {}
+            Expression::Array(range) => {
+                found.extend(
+                    range
+                        .values()
+                        .filter(|value| !matches!(value, Expression::Empty)),
+                );
+            }
+            _ => found.push(value),
+        }
+    }
+    found
+}
+
+/* mixed numbers compare after promotion, the winner keeps its own type */
+pub fn max(values: &[Expression]) -> Result<Expression, EvalError> {
+    extreme(values, Ordering::Greater, "Max")
+}
+
+pub fn min(values: &[Expression]) -> Result<Expression, EvalError> {
+    extreme(values, Ordering::Less, "Min")
+}
+
+fn extreme(values: &[Expression], wanted: Ordering, name: &str) -> Result<Expression, EvalError> {
+    let values = non_empty(values);
+    let (mut best, rest) = match values.split_first() {
+        Some((first, rest)) => (*first, rest),
+        None => return Ok(Expression::Integer(0)),
+    };
+    for expr in rest {
+        match Operands::of(expr, best) {
+            Some(operands) => {
+                if number::ordering(operands) == Some(wanted) {
+                    best = expr;
+                }
+            }
+            None => return Err(mismatch(&format!("Incompatible types in {}", name))),
+        }
+    }
+    Ok(best.clone())
+}
+
+pub fn mean(values: &[Expression]) -> Result<Expression, EvalError> {
+    let values = non_empty(values);
+    let sum = values
+        .iter()
+        .try_fold(0.0, |acc, e| match number::to_float(e) {
+            Some(f) => Ok(acc + f),
+            None => Err(mismatch("Incompatible types in Mean")),
+        })?;
+    if values.is_empty() {
+        return Err(ErrorKind::DivideByZero.into());
+    }
+    let mean = sum / values.len() as f64;
+    Ok(Expression::Float(mean))
+}
+
+/* stays an Integer until a Float comes along, overflow follows the policy */
+pub fn sum(values: &[Expression], policy: OverflowPolicy) -> Result<Expression, EvalError> {
+    non_empty(values)
+        .into_iter()
+        .try_fold(Expression::Integer(0), |acc, value| {
+            number::add(&acc, value, policy)
+                .unwrap_or_else(|| Err(mismatch("Incompatible types in Sum")))
+        })
+}
@@ -0,0 +1,289 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/* (row, col), same order the Grid takes its coordinates in */
+pub type CellPos = (usize, usize);
+
+/* top-left and bottom-right corners */
+pub type Rect = (CellPos, CellPos);
+
+fn contains(((top, left), (bottom, right)): &Rect, (row, col): CellPos) -> bool {
+    (*top..=*bottom).contains(&row) && (*left..=*right).contains(&col)
+}
+
+/* ranges are indexed by the BLOCK x BLOCK squares they touch */
+const BLOCK: usize = 16;
+const MAX_BLOCKS: usize = 1024; /* past this a range is scanned rather than indexed */
+
+fn block((row, col): CellPos) -> CellPos {
+    (row / BLOCK, col / BLOCK)
+}
+
+/* the blocks a range touches, None when there are too many to index */
+fn blocks(&(start, end): &Rect) -> Option<Vec<CellPos>> {
+    let ((top, left), (bottom, right)) = (block(start), block(end));
+    let count = (bottom - top + 1).checked_mul(right - left + 1)?;
+    if count > MAX_BLOCKS {
+        return None;
+    }
+    Some(
+        (top..=bottom)
+            .flat_map(|row| (left..=right).map(move |col| (row, col)))
+            .collect(),
+    )
+}
+
+#[derive(Debug, Default)]
+pub struct DependencyGraph {
+    precedents: HashMap<CellPos, HashSet<CellPos>>, /* cells a formula reads */
+    dependents: HashMap<CellPos, HashSet<CellPos>>, /* formulas reading a cell */
+    ranges: HashMap<CellPos, Vec<Rect>>,            /* ranges a formula reads, kept whole */
+    blocks: HashMap<CellPos, HashSet<CellPos>>,     /* formulas with a range touching a block */
+    wide: HashSet<CellPos>,                         /* formulas with a range too big to index */
+}
+
+impl DependencyGraph {
+    pub fn new() -> Self {
+        DependencyGraph::default()
+    }
+
+    pub fn set_precedents(
+        &mut self,
+        cell: CellPos,
+        precedents: HashSet<CellPos>,
+        ranges: Vec<Rect>,
+    ) {
+        self.clear(cell);
+        for precedent in &precedents {
+            self.dependents.entry(*precedent).or_default().insert(cell);
+        }
+        if !precedents.is_empty() {
+            self.precedents.insert(cell, precedents);
+        }
+        if !ranges.is_empty() {
+            match ranges.iter().map(blocks).collect::<Option<Vec<_>>>() {
+                Some(touched) => {
+                    for block in touched.into_iter().flatten() {
+                        self.blocks.entry(block).or_default().insert(cell);
+                    }
+                }
+                None => {
+                    self.wide.insert(cell);
+                }
+            }
+            self.ranges.insert(cell, ranges);
+        }
+    }
+
+    pub fn clear(&mut self, cell: CellPos) {
+        if let Some(ranges) = self.ranges.remove(&cell) {
+            if !self.wide.remove(&cell) {
+                for block in ranges.iter().filter_map(blocks).flatten() {
+                    if let Some(formulas) = self.blocks.get_mut(&block) {
+                        formulas.remove(&cell);
+                        if formulas.is_empty() {
+                            self.blocks.remove(&block);
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(old) = self.precedents.remove(&cell) {
+            for precedent in old {
+                if let Some(dependents) = self.dependents.get_mut(&precedent) {
+                    dependents.remove(&cell);
+                    if dependents.is_empty() {
+                        self.dependents.remove(&precedent);
+                    }
+                }
+            }
+        }
+    }
+
+    /* single cells a formula reads, ranges aren't expanded into it */
+    pub fn precedents(&self, cell: CellPos) -> impl Iterator<Item = &CellPos> {
+        self.precedents.get(&cell).into_iter().flatten()
+    }
+
+    /* whether the formula at cell reads target, directly or through a range */
+    pub fn reads(&self, cell: CellPos, target: CellPos) -> bool {
+        self.precedents
+            .get(&cell)
+            .is_some_and(|precedents| precedents.contains(&target))
+            || self
+                .ranges
+                .get(&cell)
+                .is_some_and(|ranges| ranges.iter().any(|range| contains(range, target)))
+    }
+
+    /* formulas reading cell, only ranges touching its block (or too wide to index) are checked */
+    pub fn dependents(&self, cell: CellPos) -> impl Iterator<Item = CellPos> + '_ {
+        let direct = self.dependents.get(&cell).into_iter().flatten().copied();
+        let ranged = self
+            .blocks
+            .get(&block(cell))
+            .into_iter()
+            .flatten()
+            .chain(&self.wide)
+            .copied()
+            .filter(move |dependent| {
+                self.ranges[dependent]
+                    .iter()
+                    .any(|range| contains(range, cell))
+            });
+        direct.chain(ranged)
+    }
+
+    /* the origins plus every cell downstream of them */
+    fn downstream(&self, origins: &[CellPos]) -> HashSet<CellPos> {
+        let mut affected: HashSet<CellPos> = origins.iter().copied().collect();
+        let mut queue: VecDeque<CellPos> = origins.iter().copied().collect();
+        while let Some(cell) = queue.pop_front() {
+            for dependent in self.dependents(cell) {
+                if affected.insert(dependent) {
+                    queue.push_back(dependent);
+                }
+            }
+        }
+        affected
+    }
+
+    /*
+     * strongly connected groups of the origins and everything downstream of
+     * them, precedents first. a group of more than one cell, or a cell
+     * reading itself, is a circular reference.
+     */
+    pub fn recalc_order(&self, origins: &[CellPos]) -> Vec<Vec<CellPos>> {
+        let affected = self.downstream(origins);
+        let mut roots: Vec<CellPos> = affected.iter().copied().collect();
+        roots.sort();
+
+        let mut tarjan = Tarjan::new(self, &affected);
+        for root in roots {
+            tarjan.run(root);
+        }
+
+        /* tarjan finishes dependents before the cells they read */
+        let mut groups = tarjan.groups;
+        groups.reverse();
+        groups
+    }
+
+    pub fn is_cyclic(&self, group: &[CellPos]) -> bool {
+        group.len() > 1 || self.reads(group[0], group[0])
+    }
+
+    /* shortest path from cell through what it reads back to itself */
+    pub fn cycle_path(&self, cell: CellPos, group: &[CellPos]) -> Vec<CellPos> {
+        let mut came_from: HashMap<CellPos, CellPos> = HashMap::new();
+        let mut queue = VecDeque::from([cell]);
+        while let Some(current) = queue.pop_front() {
+            /* groups are sorted, so are the precedents found in them */
+            let precedents: Vec<CellPos> = group
+                .iter()
+                .filter(|p| self.reads(current, **p))
+                .copied()
+                .collect();
+            for precedent in precedents {
+                if precedent == cell {
+                    let mut path = vec![cell];
+                    let mut step = current;
+                    while step != cell {
+                        path.push(step);
+                        step = came_from[&step];
+                    }
+                    path[1..].reverse();
+                    path.push(cell);
+                    return path;
+                }
+                if let Entry::Vacant(e) = came_from.entry(precedent) {
+                    e.insert(current);
+                    queue.push_back(precedent);
+                }
+            }
+        }
+        vec![cell]
+    }
+}
+
+/* iterative tarjan, so long dependency chains can't blow the stack */
+struct Tarjan<'a> {
+    graph: &'a DependencyGraph,
+    affected: &'a HashSet<CellPos>,
+    index: HashMap<CellPos, usize>,
+    lowlink: HashMap<CellPos, usize>,
+    on_stack: HashSet<CellPos>,
+    stack: Vec<CellPos>,
+    work: Vec<(CellPos, Vec<CellPos>)>,
+    groups: Vec<Vec<CellPos>>,
+}
+
+impl<'a> Tarjan<'a> {
+    fn new(graph: &'a DependencyGraph, affected: &'a HashSet<CellPos>) -> Self {
+        Tarjan {
+            graph,
+            affected,
+            index: HashMap::new(),
+            lowlink: HashMap::new(),
+            on_stack: HashSet::new(),
+            stack: Vec::new(),
+            work: Vec::new(),
+            groups: Vec::new(),
+        }
+    }
+
+    fn visit(&mut self, cell: CellPos) {
+        let next = self.index.len();
+        self.index.insert(cell, next);
+        self.lowlink.insert(cell, next);
+        self.stack.push(cell);
+        self.on_stack.insert(cell);
+
+        let mut edges: Vec<CellPos> = self
+            .graph
+            .dependents(cell)
+            .filter(|d| self.affected.contains(d))
+            .collect();
+        edges.sort_by(|a, b| b.cmp(a));
+        edges.dedup();
+        self.work.push((cell, edges));
+    }
+
+    fn run(&mut self, root: CellPos) {
+        if self.index.contains_key(&root) {
+            return;
+        }
+        self.visit(root);
+
+        while let Some((cell, edges)) = self.work.last_mut() {
+            let cell = *cell;
+            if let Some(next) = edges.pop() {
+                if !self.index.contains_key(&next) {
+                    self.visit(next);
+                } else if self.on_stack.contains(&next) {
+                    let low = self.lowlink[&cell].min(self.index[&next]);
+                    self.lowlink.insert(cell, low);
+                }
+                continue;
+            }
+
+            self.work.pop();
+            if let Some((parent, _)) = self.work.last() {
+                let low = self.lowlink[parent].min(self.lowlink[&cell]);
+                self.lowlink.insert(*parent, low);
+            }
+            if self.lowlink[&cell] == self.index[&cell] {
+                let mut group = Vec::new();
+                while let Some(member) = self.stack.pop() {
+                    self.on_stack.remove(&member);
+                    group.push(member);
+                    if member == cell {
+                        break;
+                    }
+                }
+                group.sort();
+                self.groups.push(group);
+            }
+        }
+    }
+}
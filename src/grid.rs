@@ -1,54 +1,1122 @@
-use crate::cell::{Cell, CellValue};
+use std::collections::{BTreeMap, HashSet};
+use std::rc::Rc;
 
-const ROWS: usize = 20;
-const COLS: usize = 20;
+use crate::cell::{Cell, CellValue, ErrorKind};
+use crate::error::{EvalError, FormulaError, ScriptError, Span};
+use crate::functions::{FunctionRegistry, UserFunction};
+use crate::graph::{CellPos, DependencyGraph, Rect};
+use crate::lexer::{Lexer, TokenType};
+use crate::number::OverflowPolicy;
+use crate::parser::{parse_formula, parse_statement};
+use crate::reference::{Axis, SheetEdit, A1};
+use crate::tree::{normalize, Expression};
 
+/* opt-in fixed-point evaluation of intentional circular references */
+#[derive(Clone, Copy, Debug)]
+pub struct IterationSettings {
+    pub max_iterations: usize,
+    pub epsilon: f64, /* largest change in a Float cell still counted as settled */
+}
+
+#[derive(Clone, Debug)]
+pub struct CycleReport {
+    pub cells: Vec<CellPos>,
+    pub iterations: usize,
+    pub converged: bool,
+}
+
+/* sparse, only occupied cells are stored, ordered row by row */
 pub struct Grid {
-    cells: Vec<Vec<Cell>>,
+    cells: BTreeMap<CellPos, Cell>,
+    graph: DependencyGraph,
+    functions: FunctionRegistry,
+    volatile: HashSet<CellPos>, /* formulas calling impure functions or computing references */
+    iteration: Option<IterationSettings>,
+    cycle_reports: Vec<CycleReport>,
+    overflow: OverflowPolicy,
+    reads: Option<Vec<Rect>>, /* what the running formula read, kept while computed references run */
+}
+
+impl Default for Grid {
+    fn default() -> Self {
+        Grid::new()
+    }
 }
 
 impl Grid {
     pub fn new() -> Self {
-        let cells = vec![vec![Cell::new_empty(); COLS]; ROWS];
-        Grid { cells }
+        Grid {
+            cells: BTreeMap::new(),
+            graph: DependencyGraph::new(),
+            functions: FunctionRegistry::with_builtins(),
+            volatile: HashSet::new(),
+            iteration: None,
+            cycle_reports: Vec::new(),
+            overflow: OverflowPolicy::default(),
+            reads: None,
+        }
+    }
+
+    pub fn functions(&self) -> &FunctionRegistry {
+        &self.functions
     }
 
+    /*
+     * makes an application function callable from formula text. formulas
+     * already on the sheet are recalculated so they pick it up; returns the
+     * cells whose value changed.
+     */
+    pub fn register_function<F: UserFunction + 'static>(&mut self, function: F) -> Vec<CellPos> {
+        self.functions.register_user(Rc::new(function));
+        self.track_all();
+        self.recalculate_all()
+    }
+
+    /* re-evaluate every formula on the sheet, returns the cells whose value changed */
+    pub fn recalculate_all(&mut self) -> Vec<CellPos> {
+        let origins: Vec<CellPos> = self
+            .cells
+            .iter()
+            .filter(|(_, cell)| cell.get_ast().is_some())
+            .map(|(pos, _)| *pos)
+            .collect();
+        self.recalculate_settled(&origins, None)
+    }
+
+    /* None turns circular references back into errors, returns the cells whose value changed */
+    pub fn set_iterative_calculation(
+        &mut self,
+        settings: Option<IterationSettings>,
+    ) -> Vec<CellPos> {
+        self.iteration = settings;
+        self.recalculate_all()
+    }
+
+    pub fn iterative_calculation(&self) -> Option<IterationSettings> {
+        self.iteration
+    }
+
+    /* integer overflow is an error by default, returns the cells whose value changed */
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) -> Vec<CellPos> {
+        self.overflow = policy;
+        self.recalculate_all()
+    }
+
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.overflow
+    }
+
+    /* one report per cyclic group iterated by the last recalculation */
+    pub fn cycle_reports(&self) -> &[CycleReport] {
+        &self.cycle_reports
+    }
+
+    /* None for a cell that has never been written */
     pub fn get_cell(&self, row: usize, col: usize) -> Option<&Cell> {
-        if row < ROWS && col < COLS {
-            Some(&self.cells[row][col])
-        } else {
-            None
+        self.cells.get(&(row, col))
+    }
+
+    /* a cell's value for a formula, blank if it was never written */
+    pub fn read_cell(&self, row: usize, col: usize) -> Result<Expression, EvalError> {
+        match self.get_cell(row, col) {
+            Some(cell) => Ok(Expression::from_cell_value(cell.get_value())?),
+            None => Ok(Expression::Empty),
         }
     }
 
+    /* called by evaluation for each cell or range it reads, a single cell is start == end */
+    pub(crate) fn record_read(&mut self, start: CellPos, end: CellPos) {
+        if let Some(reads) = &mut self.reads {
+            reads.push((start, end));
+        }
+    }
+
+    /* None for a cell that has never been written, nothing is stored on the way */
     pub fn get_mut_cell(&mut self, row: usize, col: usize) -> Option<&mut Cell> {
-        if row < ROWS && col < COLS {
-            Some(&mut self.cells[row][col])
+        self.cells.get_mut(&(row, col))
+    }
+
+    /* the cell about to be written, stored blank first if it wasn't yet */
+    fn cell_entry(&mut self, row: usize, col: usize) -> &mut Cell {
+        self.cells.entry((row, col)).or_insert_with(Cell::new_empty)
+    }
+
+    /* top-left and bottom-right corners of the occupied cells */
+    pub fn used_range(&self) -> Option<(CellPos, CellPos)> {
+        let first_row = self.cells.keys().next()?.0;
+        let last_row = self.cells.keys().next_back()?.0;
+        let first_col = self.cells.keys().map(|(_, col)| *col).min()?;
+        let last_col = self.cells.keys().map(|(_, col)| *col).max()?;
+        Some(((first_row, first_col), (last_row, last_col)))
+    }
+
+    /* occupied cells in row-major order */
+    pub fn cells(&self) -> impl Iterator<Item = (&CellPos, &Cell)> {
+        self.cells.iter()
+    }
+
+    /* occupied cells inside the rectangle, row by row, blank ones are never visited */
+    pub fn cells_in(
+        &self,
+        start: CellPos,
+        end: CellPos,
+    ) -> impl Iterator<Item = (&CellPos, &Cell)> {
+        let ((top, left), (bottom, right)) = normalize(start, end);
+        self.cells
+            .range((top, left)..=(bottom, right))
+            .filter(move |((_, col), _)| (left..=right).contains(col))
+    }
+
+    /* returns the downstream cells whose value changed. a blank value clears the cell */
+    pub fn set_cell_value(&mut self, row: usize, col: usize, value: CellValue) -> Vec<CellPos> {
+        if value == CellValue::Empty {
+            return self.clear_cell(row, col);
+        }
+        let cell = self.cell_entry(row, col);
+        cell.clear_formula();
+        cell.set_value(value);
+        self.track((row, col), None);
+        self.recalculate((row, col))
+    }
+
+    /* returns the downstream cells whose value changed */
+    pub fn set_cell_formula(&mut self, row: usize, col: usize, formula: String) -> Vec<CellPos> {
+        let parsed = parse_formula(&formula);
+
+        let cell = self.cell_entry(row, col);
+        cell.set_formula(formula);
+        match parsed {
+            Ok(ast) => {
+                cell.set_ast(ast.clone());
+                self.track((row, col), Some(&ast));
+            }
+            Err(e) => {
+                cell.set_failure(e);
+                self.track((row, col), None);
+            }
+        }
+        self.recalculate((row, col))
+    }
+
+    /* drops the cell from storage, returns the downstream cells whose value changed */
+    pub fn clear_cell(&mut self, row: usize, col: usize) -> Vec<CellPos> {
+        self.cells.remove(&(row, col));
+        self.track((row, col), None);
+        self.recalculate((row, col))
+    }
+
+    /*
+     * copies the cell at source into every cell from start to end, like
+     * filling a formula down a column. relative references move with the
+     * target, absolute ones stay put. returns the cells outside the filled
+     * range whose value changed.
+     */
+    pub fn fill(&mut self, source: CellPos, start: CellPos, end: CellPos) -> Vec<CellPos> {
+        let original = self.cells.get(&source).cloned();
+        let ((top, left), (bottom, right)) = normalize(start, end);
+        let mut changed = Vec::new();
+        for row in top..=bottom {
+            for col in left..=right {
+                if (row, col) == source {
+                    continue;
+                }
+                let cell = match &original {
+                    Some(cell) => cell,
+                    None => {
+                        changed.extend(self.clear_cell(row, col));
+                        continue;
+                    }
+                };
+                let updated = match (cell.get_ast(), cell.get_formula()) {
+                    (Some(ast), Some(formula)) => {
+                        let mut shifted = ast.clone();
+                        shifted.shift(row as i64 - source.0 as i64, col as i64 - source.1 as i64);
+                        self.set_cell_formula(row, col, respell(formula, ast, &shifted))
+                    }
+                    /* a formula that failed to parse is copied word for word */
+                    (None, Some(formula)) => self.set_cell_formula(row, col, formula.clone()),
+                    _ => self.set_cell_value(row, col, cell.get_value().clone()),
+                };
+                changed.extend(updated);
+            }
+        }
+
+        changed
+            .retain(|&(row, col)| !(top..=bottom).contains(&row) || !(left..=right).contains(&col));
+        changed.sort();
+        changed.dedup();
+        changed
+    }
+
+    /*
+     * runs a script of `[col, row] = expression` lines, blank lines are
+     * skipped. each value is written before the next line evaluates, so later
+     * lines see earlier ones. stops at the first line that fails; returns the
+     * cells written and the downstream cells whose value changed.
+     */
+    pub fn execute(&mut self, script: &str) -> Result<Vec<CellPos>, ScriptError> {
+        let mut changed = Vec::new();
+        for (index, line) in script.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fail = |error: FormulaError| ScriptError {
+                line: index + 1,
+                error,
+            };
+            let assignment = parse_statement(line).map_err(fail)?;
+            let written = assignment
+                .target(self)
+                .and_then(|target| Ok((target, assignment.value(self)?)));
+            let ((row, col), value) = written.map_err(|e| fail(FormulaError::Eval(e)))?;
+            changed.push((row, col));
+            changed.extend(self.set_cell_value(row, col, value));
+        }
+        changed.sort();
+        changed.dedup();
+        Ok(changed)
+    }
+
+    /* the edits below return the cells whose value changed, at their new position */
+    pub fn insert_rows(&mut self, at: usize, count: usize) -> Vec<CellPos> {
+        self.edit_sheet(SheetEdit::Insert {
+            axis: Axis::Row,
+            at,
+            count,
+        })
+    }
+
+    pub fn delete_rows(&mut self, at: usize, count: usize) -> Vec<CellPos> {
+        self.edit_sheet(SheetEdit::Delete {
+            axis: Axis::Row,
+            at,
+            count,
+        })
+    }
+
+    pub fn insert_cols(&mut self, at: usize, count: usize) -> Vec<CellPos> {
+        self.edit_sheet(SheetEdit::Insert {
+            axis: Axis::Col,
+            at,
+            count,
+        })
+    }
+
+    pub fn delete_cols(&mut self, at: usize, count: usize) -> Vec<CellPos> {
+        self.edit_sheet(SheetEdit::Delete {
+            axis: Axis::Col,
+            at,
+            count,
+        })
+    }
+
+    /*
+     * moves every cell out of the way of the edit and rewrites the formulas
+     * so their references follow. the text of a formula is only regenerated
+     * when one of its references actually changed.
+     */
+    pub fn edit_sheet(&mut self, edit: SheetEdit) -> Vec<CellPos> {
+        let cells = std::mem::take(&mut self.cells);
+        for (pos, mut cell) in cells {
+            let pos = match edit.cell(pos) {
+                Some(pos) => pos,
+                None => continue,
+            };
+            if let (Some(ast), Some(formula)) = (cell.get_ast(), cell.get_formula()) {
+                let mut rewritten = ast.clone();
+                rewritten.rewrite(edit);
+                let respelled = respell(formula, ast, &rewritten);
+                if respelled != *formula {
+                    let formula = respelled;
+                    /* parse the new text again so diagnostics line up with it */
+                    let parsed = parse_formula(&formula);
+                    cell.set_formula(formula);
+                    match parsed {
+                        Ok(ast) => cell.set_ast(ast),
+                        Err(e) => cell.set_failure(e),
+                    }
+                }
+            }
+            self.cells.insert(pos, cell);
+        }
+
+        self.graph = DependencyGraph::new();
+        self.volatile.clear();
+        self.track_all();
+        self.recalculate_all()
+    }
+
+    /* what a cell reads and whether it has to recalculate on every change */
+    fn track(&mut self, pos: CellPos, ast: Option<&Expression>) {
+        let ast = match ast {
+            Some(ast) => ast,
+            None => {
+                self.graph.clear(pos);
+                self.volatile.remove(&pos);
+                return;
+            }
+        };
+
+        self.link(pos, ast, Vec::new());
+
+        let impure = ast.calls().into_iter().any(|name| {
+            self.functions
+                .lookup(name)
+                .is_some_and(|function| !function.is_pure())
+        });
+        if impure || ast.has_computed_references() {
+            self.volatile.insert(pos);
         } else {
-            None
+            self.volatile.remove(&pos);
+        }
+    }
+
+    /* edges for what the text of a formula reads, plus what its last run read */
+    fn link(&mut self, pos: CellPos, ast: &Expression, reads: Vec<Rect>) {
+        let mut precedents: HashSet<CellPos> = ast.references().into_iter().collect();
+        let mut ranges = ast.range_references();
+        for (start, end) in reads {
+            if start == end {
+                precedents.insert(start);
+            } else {
+                ranges.push((start, end));
+            }
+        }
+        self.graph.set_precedents(pos, precedents, ranges);
+    }
+
+    fn track_all(&mut self) {
+        let formulas: Vec<(CellPos, Expression)> = self
+            .cells
+            .iter()
+            .filter_map(|(pos, cell)| cell.get_ast().map(|ast| (*pos, ast.clone())))
+            .collect();
+        for (pos, ast) in &formulas {
+            self.track(*pos, Some(ast));
         }
     }
 
-    pub fn set_cell_value(&mut self, row: usize, col: usize, value: CellValue) {
-        if row < ROWS && col < COLS {
-            self.cells[row][col].set_value(value);
+    fn snapshot(&self, pos: CellPos) -> Cell {
+        self.cells
+            .get(&pos)
+            .cloned()
+            .unwrap_or_else(Cell::new_empty)
+    }
+
+    /* after an edit to origin, volatile formulas always come along */
+    fn recalculate(&mut self, origin: CellPos) -> Vec<CellPos> {
+        self.recalculate_settled(&[origin], Some(origin))
+    }
+
+    /*
+     * what a computed reference reads is only known once it ran, so volatile
+     * formulas go after everything else has been recalculated. each run adds
+     * what it read to the graph, one computed reference can read another, or
+     * itself. those repeat until a pass changes nothing, a cycle found on the
+     * way is reported like any other.
+     */
+    fn recalculate_settled(
+        &mut self,
+        origins: &[CellPos],
+        edited: Option<CellPos>,
+    ) -> Vec<CellPos> {
+        self.cycle_reports.clear();
+
+        let mut volatile: Vec<CellPos> = self.volatile.iter().copied().collect();
+        volatile.sort();
+        let computed: Vec<(CellPos, Expression)> = volatile
+            .iter()
+            .filter_map(|&(row, col)| {
+                let ast = self.get_cell(row, col)?.get_ast()?;
+                ast.has_computed_references()
+                    .then(|| ((row, col), ast.clone()))
+            })
+            .collect();
+        /* reads from an earlier run may be stale, start over from the text */
+        for (pos, ast) in &computed {
+            self.link(*pos, ast, Vec::new());
+        }
+        let computed: Vec<CellPos> = computed.into_iter().map(|(pos, _)| pos).collect();
+
+        let mut changed = self.recalculate_cells(origins, edited);
+        changed.extend(self.recalculate_cells(&volatile, edited));
+        for _ in 0..computed.len() {
+            let again = self.recalculate_cells(&computed, edited);
+            if again.is_empty() {
+                break;
+            }
+            changed.extend(again);
         }
+
+        changed.sort();
+        changed.dedup();
+        changed
     }
 
-    pub fn set_cell_formula(&mut self, row: usize, col: usize, formula: String) {
-        if row < ROWS && col < COLS {
-            self.cells[row][col].set_formula(formula);
+    /*
+     * re-evaluate the origins and everything downstream of them in
+     * dependency order. cells on a cycle get the path as an error, unless
+     * iterative calculation is on, then the whole group is iterated to a
+     * fixed point. the edited cell is left out of the changed list.
+     */
+    fn recalculate_cells(&mut self, origins: &[CellPos], edited: Option<CellPos>) -> Vec<CellPos> {
+        let mut changed = Vec::new();
+        for group in self.graph.recalc_order(origins) {
+            let before: Vec<Cell> = group.iter().map(|&pos| self.snapshot(pos)).collect();
+
+            if !self.graph.is_cyclic(&group) {
+                let (row, col) = group[0];
+                self.evaluate_cell(row, col);
+            } else if let Some(settings) = self.iteration {
+                let report = self.iterate_group(&group, settings);
+                /* a group iterated again by a later pass keeps only its last report */
+                self.cycle_reports.retain(|old| old.cells != report.cells);
+                self.cycle_reports.push(report);
+            } else {
+                for &(row, col) in &group {
+                    let path = self.graph.cycle_path((row, col), &group);
+                    self.cell_entry(row, col).set_error(ErrorKind::Cycle(path));
+                }
+            }
+
+            for (&pos, before) in group.iter().zip(before) {
+                let after = self.snapshot(pos);
+                if Some(pos) != edited && before.get_value() != after.get_value() {
+                    changed.push(pos);
+                }
+            }
         }
+        changed
     }
 
-    /* debug printer, replace with curses later */
+    /*
+     * gauss-seidel sweeps over the group, starting from the current values. an
+     * error would only feed itself around the loop, those cells start blank.
+     * a group still holding an error has not converged.
+     */
+    fn iterate_group(&mut self, group: &[CellPos], settings: IterationSettings) -> CycleReport {
+        for &(row, col) in group {
+            if let Some(cell) = self.get_mut_cell(row, col) {
+                if cell.get_error().is_some() {
+                    cell.set_value(CellValue::Empty);
+                }
+            }
+        }
+
+        let mut iterations = 0;
+        let mut converged = false;
+        while iterations < settings.max_iterations {
+            iterations += 1;
+            let mut settled = true;
+            for &(row, col) in group {
+                let before = self.snapshot((row, col));
+                self.evaluate_cell(row, col);
+                let after = self.snapshot((row, col));
+                let close = match (before.get_value(), after.get_value()) {
+                    (CellValue::Float(l), CellValue::Float(r)) => (l - r).abs() <= settings.epsilon,
+                    (l, r) => l == r,
+                };
+                if !close {
+                    settled = false;
+                }
+            }
+            if settled {
+                converged = group.iter().all(|&(row, col)| {
+                    self.get_cell(row, col)
+                        .is_none_or(|cell| cell.get_error().is_none())
+                });
+                break;
+            }
+        }
+
+        CycleReport {
+            cells: group.to_vec(),
+            iterations,
+            converged,
+        }
+    }
+
+    /* re-run the cached ast, the cell keeps the value or the failure */
+    fn evaluate_cell(&mut self, row: usize, col: usize) {
+        let ast = match self.get_cell(row, col).and_then(|cell| cell.get_ast()) {
+            Some(ast) => ast.clone(),
+            None => return,
+        };
+
+        if ast.has_computed_references() {
+            self.reads = Some(Vec::new());
+        }
+        let result = ast
+            .evaluate(self)
+            .and_then(|value| value.to_cell_value().map_err(EvalError::from));
+        if let Some(reads) = self.reads.take() {
+            self.link((row, col), &ast, reads);
+        }
+        let cell = self.cell_entry(row, col);
+        match result {
+            Ok(value) => cell.set_value(value),
+            Err(e) => cell.set_failure(FormulaError::Eval(e)),
+        }
+    }
+
+    /* debug printer, replace with curses later. only visits occupied cells */
     pub fn render(&self) {
-        for row in 0..ROWS {
-            for col in 0..COLS {
-                let cell = &self.cells[row][col];
-                print!("{:<12}", cell);
+        let ((first_row, first_col), _) = match self.used_range() {
+            Some(range) => range,
+            None => return,
+        };
+        let (mut row, mut next_col) = (first_row, first_col);
+        for (&(cell_row, col), cell) in &self.cells {
+            if cell_row != row {
+                for _ in row..cell_row {
+                    println!();
+                }
+                row = cell_row;
+                next_col = first_col;
+            }
+            for _ in next_col..col {
+                print!("{:<12}", "");
+            }
+            print!("{:<12}", cell);
+            next_col = col + 1;
+        }
+        println!();
+    }
+}
+
+/*
+ * the formula text with only the references that moved written anew, spacing,
+ * templates and everything else stay as typed. before is the tree parsed from
+ * formula, after the same tree shifted or rewritten, spans match up.
+ */
+fn respell(formula: &str, before: &Expression, after: &Expression) -> String {
+    let original = before.spanned_references();
+    let mut edits: Vec<(Span, String)> = Vec::new();
+    for (span, expr) in after.spanned_references() {
+        let old = match original.iter().find(|(old, _)| *old == span) {
+            Some((_, old)) => old,
+            None => continue,
+        };
+        let text = match (old, expr) {
+            (Expression::Error(_), _) | (_, Expression::Range(..)) => continue,
+            (_, Expression::Error(kind)) => kind.code().to_string(),
+            _ if old.literal_position() == expr.literal_position() => continue,
+            _ => match respell_reference(&formula[span.start..span.end], expr) {
+                Some(text) => text,
+                None => continue,
+            },
+        };
+        if !edits.iter().any(|(done, _)| *done == span) {
+            edits.push((span, text));
+        }
+    }
+
+    edits.sort_by_key(|(span, _)| span.start);
+    let mut respelled = String::new();
+    let mut copied = 0;
+    for (span, text) in edits {
+        respelled.push_str(&formula[copied..span.start]);
+        respelled.push_str(&text);
+        copied = span.end;
+    }
+    respelled.push_str(&formula[copied..]);
+    respelled
+}
+
+/* B7 keeps its anchors, #[col, row] its spacing when both are plain numbers */
+fn respell_reference(text: &str, reference: &Expression) -> Option<String> {
+    let (row, col) = reference.literal_position()?;
+    let anchor = match reference {
+        Expression::CellRValue(_, _, anchor) => *anchor,
+        _ => return None,
+    };
+    if !text.starts_with('#') {
+        return Some(A1::anchored(row, col, anchor).to_string());
+    }
+
+    let tokens = Lexer::new(text).tokenize().unwrap_or_default();
+    let plain = [
+        TokenType::CellReference,
+        TokenType::BracketOpen,
+        TokenType::IntegerLiteral,
+        TokenType::Comma,
+        TokenType::IntegerLiteral,
+        TokenType::BracketClose,
+        TokenType::EOF,
+    ];
+    if !tokens.iter().map(|token| &token.token_type).eq(&plain) {
+        return Some(format!("#[{}, {}]", col, row));
+    }
+    let (c, r) = (&tokens[2], &tokens[4]);
+    Some(format!(
+        "{}{}{}{}{}",
+        &text[..c.start_index],
+        col,
+        &text[c.end_index..r.start_index],
+        row,
+        &text[r.end_index..]
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{LexError, ParseError};
+    use crate::number::MAX_BITS;
+    use crate::reference::Notation;
+
+    fn value(grid: &Grid, row: usize, col: usize) -> CellValue {
+        grid.get_cell(row, col).unwrap().get_value().clone()
+    }
+
+    #[test]
+    fn huge_ranges_only_visit_occupied_cells() {
+        let mut grid = Grid::new();
+        grid.set_cell_value(0, 0, CellValue::Int(1));
+        grid.set_cell_value(5, 0, CellValue::Int(2));
+
+        grid.set_cell_formula(
+            0,
+            7,
+            "sum(#[0,0]:#[0, 1 + 9223372036854775806])".to_string(),
+        );
+        grid.set_cell_formula(1, 7, "sum(#[0,0]:#[0, 4000000000])".to_string());
+        grid.set_cell_formula(2, 7, "sum(A1:E1000000)".to_string());
+        grid.set_cell_formula(3, 7, "sum(A1:A4000000000)".to_string());
+        for row in 0..4 {
+            assert_eq!(value(&grid, row, 7), CellValue::Int(3));
+        }
+
+        /* a literal range is tracked as a rectangle, writes inside it still propagate */
+        let changed = grid.set_cell_value(3_999_999_999, 0, CellValue::Int(10));
+        assert!(changed.contains(&(3, 7)));
+        assert_eq!(value(&grid, 3, 7), CellValue::Int(13));
+    }
+
+    #[test]
+    fn ranges_are_found_across_index_blocks() {
+        let mut grid = Grid::new();
+        grid.set_cell_formula(0, 7, "sum(A15:C18)".to_string());
+        let changed = grid.set_cell_value(17, 2, CellValue::Int(4));
+        assert_eq!(changed, vec![(0, 7)]);
+        assert_eq!(value(&grid, 0, 7), CellValue::Int(4));
+
+        /* the old range stops reporting once the formula reads another */
+        grid.set_cell_formula(0, 7, "sum(A1:A2)".to_string());
+        assert!(grid.set_cell_value(14, 0, CellValue::Int(1)).is_empty());
+        assert_eq!(grid.set_cell_value(1, 0, CellValue::Int(2)), vec![(0, 7)]);
+    }
+
+    fn formula(grid: &Grid, row: usize, col: usize) -> &str {
+        grid.get_cell(row, col).unwrap().get_formula().unwrap()
+    }
+
+    #[test]
+    fn fill_only_respells_references() {
+        let mut grid = Grid::new();
+        grid.set_cell_formula(0, 1, "\"x${A1}\"  ++  #[0,0] ++ $A$1".to_string());
+        grid.set_cell_formula(0, 2, "sum( A1:A2 )*2".to_string());
+        grid.fill((0, 1), (1, 1), (1, 2));
+        assert_eq!(formula(&grid, 1, 1), "\"x${A2}\"  ++  #[0,0] ++ $A$1");
+        assert_eq!(formula(&grid, 1, 2), "\"x${B2}\"  ++  #[0,0] ++ $A$1");
+
+        grid.fill((0, 2), (1, 3), (1, 3));
+        assert_eq!(formula(&grid, 1, 3), "sum( B2:B3 )*2");
+        grid.set_cell_formula(1, 4, "A1 + B2".to_string());
+        grid.fill((1, 4), (0, 4), (0, 4));
+        assert_eq!(formula(&grid, 0, 4), "#REF! + B1");
+    }
+
+    #[test]
+    fn sheet_edits_only_respell_references() {
+        let mut grid = Grid::new();
+        grid.set_cell_formula(0, 7, "#[0,0] + 1".to_string());
+        grid.set_cell_formula(1, 7, "\"n=${ A1 }\"".to_string());
+        grid.insert_rows(0, 1);
+        assert_eq!(formula(&grid, 1, 7), "#[0,1] + 1");
+        assert_eq!(formula(&grid, 2, 7), "\"n=${ A2 }\"");
+    }
+
+    /* sum(A3:A6) at H10 after the edit */
+    fn edited_range(edit: impl FnOnce(&mut Grid) -> Vec<CellPos>) -> String {
+        let mut grid = Grid::new();
+        grid.set_cell_formula(9, 7, "sum(A3:A6)".to_string());
+        edit(&mut grid);
+        let (&pos, _) = grid.cells.iter().next().unwrap();
+        formula(&grid, pos.0, pos.1).to_string()
+    }
+
+    #[test]
+    fn sheet_edits_follow_ranges() {
+        /* a delete across the start keeps what is left of the range */
+        assert_eq!(edited_range(|grid| grid.delete_rows(1, 3)), "sum(A2:A3)");
+        /* across the end */
+        assert_eq!(edited_range(|grid| grid.delete_rows(4, 4)), "sum(A3:A4)");
+        /* all of it */
+        assert_eq!(edited_range(|grid| grid.delete_rows(2, 4)), "sum(#REF!)");
+        /* an insert inside stretches it */
+        assert_eq!(edited_range(|grid| grid.insert_rows(3, 2)), "sum(A3:A8)");
+        assert_eq!(edited_range(|grid| grid.insert_cols(0, 1)), "sum(B3:B6)");
+    }
+
+    #[test]
+    fn sheet_edit_spans() {
+        let delete = |at, count| SheetEdit::Delete {
+            axis: Axis::Row,
+            at,
+            count,
+        };
+        /* rows 2..=5, the deleted part is cut off */
+        assert_eq!(delete(1, 3).span(2, 5), Some((1, 2)));
+        assert_eq!(delete(4, 4).span(2, 5), Some((2, 3)));
+        assert_eq!(delete(3, 2).span(2, 5), Some((2, 3)));
+        assert_eq!(delete(2, 4).span(2, 5), None);
+        assert_eq!(delete(0, 9).span(2, 5), None);
+
+        let insert = SheetEdit::Insert {
+            axis: Axis::Row,
+            at: 3,
+            count: 2,
+        };
+        assert_eq!(insert.span(2, 5), Some((2, 7)));
+        assert_eq!(insert.span(3, 5), Some((5, 7)));
+        /* corners given bottom first stay that way */
+        assert_eq!(insert.range((5, 0), (2, 1)), Some(((7, 0), (2, 1))));
+        assert_eq!(delete(2, 4).range((5, 0), (2, 1)), None);
+    }
+
+    #[test]
+    fn conversion_keywords_ignore_case() {
+        let mut grid = Grid::new();
+        grid.set_cell_formula(0, 0, "INT(2.5)".to_string());
+        grid.set_cell_formula(0, 1, "Float 2 / 4".to_string());
+        grid.set_cell_formula(0, 2, "int(2.5) + FLOAT(1)".to_string());
+        assert_eq!(value(&grid, 0, 0), CellValue::Int(2));
+        assert_eq!(value(&grid, 0, 1), CellValue::Float(0.5));
+        assert_eq!(value(&grid, 0, 2), CellValue::Float(3.0));
+    }
+
+    #[test]
+    fn unary_operands_of_power_keep_their_brackets() {
+        for (source, expected) in [
+            ("(-A1) ** 2", CellValue::Int(9)),
+            ("-A1 ** 2", CellValue::Int(-9)),
+            ("(!true) == false", CellValue::Bool(true)),
+        ] {
+            let ast = parse_formula(source).unwrap();
+            let mut grid = Grid::new();
+            grid.set_cell_value(0, 0, CellValue::Int(3));
+            grid.set_cell_formula(0, 1, source.to_string());
+            grid.set_cell_formula(0, 2, ast.serialize_with(Notation::A1));
+            assert_eq!(value(&grid, 0, 1), expected, "{}", source);
+            assert_eq!(value(&grid, 0, 2), expected, "{}", formula(&grid, 0, 2));
+        }
+
+        let mut grid = Grid::new();
+        grid.set_cell_formula(0, 1, "(-A1) ** 2".to_string());
+        grid.fill((0, 1), (1, 1), (1, 1));
+        assert_eq!(formula(&grid, 1, 1), "(-A2) ** 2");
+    }
+
+    /* formula evaluated at B1 with A1 = i64::MIN */
+    fn overflowing(policy: OverflowPolicy, formula: &str) -> CellValue {
+        let mut grid = Grid::new();
+        grid.set_overflow_policy(policy);
+        grid.set_cell_value(0, 0, CellValue::Int(i64::MIN));
+        grid.set_cell_formula(0, 1, formula.to_string());
+        value(&grid, 0, 1)
+    }
+
+    #[test]
+    fn overflow_follows_the_policy() {
+        let big = |text: &str| CellValue::BigInt(text.parse().unwrap());
+        let cases = [
+            (
+                "9223372036854775807 + 1",
+                9.223372036854776e18,
+                big("9223372036854775808"),
+            ),
+            (
+                "9223372036854775807 * 2",
+                1.8446744073709552e19,
+                big("18446744073709551614"),
+            ),
+            ("2 ** 63", 9.223372036854776e18, big("9223372036854775808")),
+            ("1 << 63", 9.223372036854776e18, big("9223372036854775808")),
+            (
+                "3 << 100",
+                3.802951800684688e30,
+                big("3802951800684688204490109616128"),
+            ),
+            ("-A1", 9.223372036854776e18, big("9223372036854775808")),
+            ("int(1e300)", 1e300, big(&format!("{:.0}", 1e300))),
+        ];
+        for (formula, float, exact) in cases {
+            assert!(
+                matches!(
+                    overflowing(OverflowPolicy::Error, formula),
+                    CellValue::Error(ErrorKind::Overflow(_))
+                ),
+                "{}",
+                formula
+            );
+            let got = overflowing(OverflowPolicy::Float, formula);
+            assert_eq!(got, CellValue::Float(float), "{}", formula);
+            assert_eq!(
+                overflowing(OverflowPolicy::BigInt, formula),
+                exact,
+                "{}",
+                formula
+            );
+        }
+    }
+
+    #[test]
+    fn shift_amounts() {
+        for policy in [
+            OverflowPolicy::Error,
+            OverflowPolicy::Float,
+            OverflowPolicy::BigInt,
+        ] {
+            /* a right shift of 64 or more leaves only the sign */
+            assert_eq!(overflowing(policy, "1 >> 64"), CellValue::Int(0));
+            assert_eq!(overflowing(policy, "-1 >> 64"), CellValue::Int(-1));
+            assert_eq!(overflowing(policy, "0 << 100000"), CellValue::Int(0));
+            for formula in ["1 << -1", "1 >> -1"] {
+                assert!(matches!(
+                    overflowing(policy, formula),
+                    CellValue::Error(ErrorKind::TypeMismatch(_))
+                ));
             }
-            println!();
         }
+        assert_eq!(
+            overflowing(OverflowPolicy::BigInt, "1 << 64"),
+            CellValue::BigInt("18446744073709551616".parse().unwrap())
+        );
+        assert_eq!(
+            overflowing(OverflowPolicy::BigInt, "(1 << 70) >> 64"),
+            CellValue::Int(64)
+        );
+
+        /* a big integer stops at MAX_BITS, a Float just goes infinite */
+        let widest = format!("1 << {}", MAX_BITS - 1);
+        assert!(matches!(
+            overflowing(OverflowPolicy::BigInt, &widest),
+            CellValue::BigInt(_)
+        ));
+        for formula in [format!("1 << {}", MAX_BITS), format!("2 ** {}", MAX_BITS)] {
+            assert!(matches!(
+                overflowing(OverflowPolicy::BigInt, &formula),
+                CellValue::Error(ErrorKind::Overflow(_))
+            ));
+            assert_eq!(
+                overflowing(OverflowPolicy::Float, &formula),
+                CellValue::Float(f64::INFINITY)
+            );
+        }
+    }
+
+    #[test]
+    fn string_escapes() {
+        let mut grid = Grid::new();
+        grid.set_cell_formula(0, 0, r#""a\"b\\c\nd\u{41}""#.to_string());
+        assert_eq!(
+            value(&grid, 0, 0),
+            CellValue::String("a\"b\\c\ndA".to_string())
+        );
+
+        let lexed = |source: &str| match parse_formula(source) {
+            Err(FormulaError::Lex(e)) => e,
+            other => panic!("{} lexed as {:?}", source, other),
+        };
+        let span = |start, end| Span::new(start, end);
+        assert_eq!(
+            lexed(r#""a\qb""#),
+            LexError::InvalidEscape { span: span(2, 4) }
+        );
+        assert_eq!(
+            lexed(r#""a\u{110000}""#),
+            LexError::InvalidEscape { span: span(2, 12) }
+        );
+        /* the whole string is underlined, up to the end of the formula */
+        for source in [r#""abc"#, r#""abc\"#, r#""${"#, r#""${1}"#] {
+            assert_eq!(
+                lexed(source),
+                LexError::UnterminatedString {
+                    span: span(0, source.len())
+                },
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn template_errors_point_inside_the_braces() {
+        let mut grid = Grid::new();
+        grid.set_cell_formula(0, 0, r#""x${ 1 + "s" }y""#.to_string());
+        let cell = grid.get_cell(0, 0).unwrap();
+        assert!(matches!(
+            cell.get_value(),
+            CellValue::Error(ErrorKind::TypeMismatch(_))
+        ));
+        assert_eq!(
+            cell.render_diagnostic().unwrap(),
+            "\"x${ 1 + \"s\" }y\"\n     ^^^^^^^\nIncompatible types for addition"
+        );
+    }
+
+    #[test]
+    fn number_literals() {
+        for (source, expected) in [
+            ("0xFF", CellValue::Int(255)),
+            ("0b1010", CellValue::Int(10)),
+            ("0o17", CellValue::Int(15)),
+            ("1_000", CellValue::Int(1000)),
+            (".5", CellValue::Float(0.5)),
+            ("5.", CellValue::Float(5.0)),
+            ("1e-9", CellValue::Float(1e-9)),
+            ("-9223372036854775808", CellValue::Int(i64::MIN)),
+        ] {
+            let mut grid = Grid::new();
+            grid.set_cell_formula(0, 0, source.to_string());
+            assert_eq!(value(&grid, 0, 0), expected, "{}", source);
+        }
+
+        for source in ["1__0", "0x", "7abc", "1_", "1e"] {
+            assert_eq!(
+                parse_formula(source).unwrap_err(),
+                FormulaError::Lex(LexError::InvalidNumber {
+                    text: source.to_string(),
+                    span: Span::new(0, source.len()),
+                }),
+                "{}",
+                source
+            );
+        }
+        /* only negating it brings the literal into range */
+        for (source, start) in [("9223372036854775808", 0), ("-9223372036854775808 ** 1", 1)] {
+            assert_eq!(
+                parse_formula(source).unwrap_err(),
+                FormulaError::Parse(ParseError::OutOfRange {
+                    found: "9223372036854775808".to_string(),
+                    span: Span::new(start, start + 19),
+                }),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn computed_references_see_recalculated_values() {
+        let mut grid = Grid::new();
+        grid.set_cell_value(0, 0, CellValue::Int(1));
+        grid.set_cell_formula(0, 1, "A1*2".to_string());
+        grid.set_cell_formula(0, 2, "#[0+1, 0]".to_string());
+        assert_eq!(value(&grid, 0, 2), CellValue::Int(2));
+
+        let changed = grid.set_cell_value(0, 0, CellValue::Int(5));
+        assert_eq!(value(&grid, 0, 1), CellValue::Int(10));
+        assert_eq!(value(&grid, 0, 2), CellValue::Int(10));
+        assert_eq!(changed, vec![(0, 1), (0, 2)]);
+    }
+
+    #[test]
+    fn computed_reference_to_its_own_cell_is_a_cycle() {
+        let mut grid = Grid::new();
+        grid.set_cell_formula(0, 0, "#[0+0, 0] + 1".to_string());
+        assert_eq!(
+            value(&grid, 0, 0),
+            CellValue::Error(ErrorKind::Cycle(vec![(0, 0), (0, 0)]))
+        );
+        /* stays an error rather than growing with every edit */
+        grid.set_cell_value(5, 5, CellValue::Int(1));
+        assert!(matches!(
+            value(&grid, 0, 0),
+            CellValue::Error(ErrorKind::Cycle(_))
+        ));
+
+        grid.set_cell_formula(1, 0, "#[1+0, 1] + 1".to_string());
+        grid.set_cell_formula(1, 1, "#[0+0, 1] + 1".to_string());
+        assert_eq!(
+            value(&grid, 1, 0),
+            CellValue::Error(ErrorKind::Cycle(vec![(1, 0), (1, 1), (1, 0)]))
+        );
+
+        /* once the computed reference points elsewhere the cycle is gone */
+        grid.set_cell_formula(2, 0, "#[D4, 2] + 1".to_string());
+        assert!(matches!(
+            value(&grid, 2, 0),
+            CellValue::Error(ErrorKind::Cycle(_))
+        ));
+        grid.set_cell_value(3, 3, CellValue::Int(3));
+        assert_eq!(value(&grid, 2, 0), CellValue::Int(1));
+    }
+
+    #[test]
+    fn range_containing_its_own_cell_is_a_cycle() {
+        let mut grid = Grid::new();
+        grid.set_cell_formula(1, 0, "sum(A1:A1000000)".to_string());
+        assert!(matches!(
+            value(&grid, 1, 0),
+            CellValue::Error(ErrorKind::Cycle(_))
+        ));
+    }
+
+    fn iterate(max_iterations: usize) -> Option<IterationSettings> {
+        Some(IterationSettings {
+            max_iterations,
+            epsilon: 1e-9,
+        })
+    }
+
+    #[test]
+    fn enabling_iteration_recovers_an_existing_cycle() {
+        let mut grid = Grid::new();
+        grid.set_cell_value(0, 0, CellValue::Int(9));
+        grid.set_cell_formula(0, 1, "A1 + C1".to_string());
+        grid.set_cell_formula(0, 2, "B1 * 0.1".to_string());
+        assert!(matches!(
+            value(&grid, 0, 1),
+            CellValue::Error(ErrorKind::Cycle(_))
+        ));
+
+        let changed = grid.set_iterative_calculation(iterate(100));
+        assert!(changed.contains(&(0, 1)) && changed.contains(&(0, 2)));
+        let CellValue::Float(b1) = value(&grid, 0, 1) else {
+            panic!("B1 did not settle to a number");
+        };
+        assert!((b1 - 10.0).abs() < 1e-6);
+        let report = &grid.cycle_reports()[0];
+        assert_eq!(report.cells, vec![(0, 1), (0, 2)]);
+        assert!(report.converged);
+
+        grid.set_iterative_calculation(None);
+        assert!(matches!(
+            value(&grid, 0, 1),
+            CellValue::Error(ErrorKind::Cycle(_))
+        ));
+        assert!(grid.cycle_reports().is_empty());
+    }
+
+    #[test]
+    fn diverging_or_failing_groups_do_not_converge() {
+        let mut grid = Grid::new();
+        grid.set_iterative_calculation(iterate(10));
+        grid.set_cell_formula(0, 0, "B1 + 1".to_string());
+        grid.set_cell_formula(0, 1, "A1".to_string());
+        let report = &grid.cycle_reports()[0];
+        assert_eq!(report.iterations, 10);
+        assert!(!report.converged);
+
+        /* an error settles into itself, but is not a converged value */
+        grid.set_cell_formula(0, 1, "A1 / 0".to_string());
+        let report = &grid.cycle_reports()[0];
+        assert!(matches!(
+            value(&grid, 0, 1),
+            CellValue::Error(ErrorKind::DivideByZero)
+        ));
+        assert!(!report.converged);
     }
 }
@@ -1,5 +1,9 @@
 use std::str::Chars;
 
+use crate::error::{LexError, Span};
+use crate::reference::A1;
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, Clone)]
 pub enum TokenType {
     IntegerLiteral,
@@ -7,6 +11,8 @@ pub enum TokenType {
     StringLiteral,
     BooleanLiteral,
     CellReference,
+    A1Reference,
+    ErrorLiteral,
     Identifier,
     Eq,
     BinaryOp(String),
@@ -16,8 +22,8 @@ pub enum TokenType {
     BracketOpen,
     BracketClose,
     Comma,
+    Colon,
     EOF,
-    Unknown,
 }
 
 #[derive(Debug, Clone)]
@@ -45,6 +51,10 @@ impl Token {
             end_index,
         }
     }
+
+    pub fn span(&self) -> Span {
+        Span::new(self.start_index, self.end_index)
+    }
 }
 
 pub struct Lexer<'a> {
@@ -52,7 +62,6 @@ pub struct Lexer<'a> {
     current_char: Option<char>,
     current_index: usize,
     start_index: usize,
-    text: &'a str,
 }
 
 impl<'a> Lexer<'a> {
@@ -62,21 +71,17 @@ impl<'a> Lexer<'a> {
             current_char: None,
             current_index: 0,
             start_index: 0,
-            text: input,
         };
         lexer.advance();
         lexer
     }
 
+    /* current_index is the byte offset of current_char */
     fn advance(&mut self) {
-        self.current_char = self.input.next();
         if let Some(c) = self.current_char {
             self.current_index += c.len_utf8();
         }
-    }
-
-    fn peek(&self) -> Option<char> {
-        self.input.clone().next()
+        self.current_char = self.input.next();
     }
 
     fn capture<F>(&mut self, mut predicate: F) -> String
@@ -95,24 +100,56 @@ impl<'a> Lexer<'a> {
         result
     }
 
-    pub fn tokenize(&mut self) -> Vec<Token> {
+    pub fn tokenize(&mut self) -> Result<Vec<Token>, LexError> {
         let mut tokens = Vec::new();
+        self.lex_tokens(&mut tokens)?;
+        /* only a } with no template to close stops lex_tokens early */
+        if let Some(c) = self.current_char {
+            self.advance();
+            return Err(LexError::UnexpectedCharacter { found: c, span: Span::new(self.current_index - c.len_utf8(), self.current_index) });
+        }
+
+        tokens.push(Token::new(TokenType::EOF, "".to_string(), self.current_index, self.current_index));
+        Ok(tokens)
+    }
+
+    /* lexes until the input runs out or a } ends the expression of a ${...} */
+    fn lex_tokens(&mut self, tokens: &mut Vec<Token>) -> Result<(), LexError> {
         while let Some(c) = self.current_char {
             self.start_index = self.current_index;
 
             let token = match c {
-                '0'..='9' => self.lex_number(),
-                '"' => self.lex_string(),
+                '0'..='9' => self.lex_number()?,
+                '.' if matches!(self.peek(), Some('0'..='9')) => self.lex_number()?,
+                '"' => {
+                    self.lex_string(tokens)?;
+                    continue;
+                }
+                '}' => return Ok(()),
                 '=' => {
                     self.advance();
-                    Token::new(TokenType::Eq, "=".to_string(), self.start_index, self.current_index)
+                    if self.current_char == Some('=') {
+                        self.advance();
+                        Token::new(TokenType::BinaryOp("==".to_string()), "==".to_string(), self.start_index, self.current_index)
+                    } else {
+                        Token::new(TokenType::Eq, "=".to_string(), self.start_index, self.current_index)
+                    }
+                }
+                '-' if Lexer::is_prefix(tokens.last()) => {
+                    self.advance();
+                    Token::new(TokenType::UnaryOp("-".to_string()), "-".to_string(), self.start_index, self.current_index)
                 }
                 '+' | '-' | '*' | '/' | '%' | '^' | '&' | '|' => {
                     self.lex_binary_op(c)
                 }
                 '~' => {
                     self.advance();
-                    Token::new(TokenType::UnaryOp("~".to_string()), "~".to_string(), self.start_index, self.current_index)
+                    if self.current_char == Some('=') {
+                        self.advance();
+                        Token::new(TokenType::BinaryOp("~=".to_string()), "~=".to_string(), self.start_index, self.current_index)
+                    } else {
+                        Token::new(TokenType::UnaryOp("~".to_string()), "~".to_string(), self.start_index, self.current_index)
+                    }
                 }
                 '<' | '>' | '!' => self.lex_comparison(),
                 '(' => {
@@ -127,9 +164,16 @@ impl<'a> Lexer<'a> {
                     self.advance();
                     Token::new(TokenType::Comma, ",".to_string(), self.start_index, self.current_index)
                 }
+                ':' => {
+                    self.advance();
+                    Token::new(TokenType::Colon, ":".to_string(), self.start_index, self.current_index)
+                }
                 '#' => {
                     self.advance();
-                    Token::new(TokenType::CellReference, "#".to_string(), self.start_index, self.current_index)
+                    match self.current_char {
+                        Some(c) if c.is_ascii_uppercase() => self.lex_error_literal(),
+                        _ => Token::new(TokenType::CellReference, "#".to_string(), self.start_index, self.current_index),
+                    }
                 }
                 '[' => {
                     self.advance();
@@ -143,43 +187,222 @@ impl<'a> Lexer<'a> {
                     self.advance();
                     continue;
                 }
-                c if c.is_alphabetic() || c == '_' => self.lex_identifier_or_boolean(),
+                c if c.is_alphabetic() || c == '_' || c == '$' => self.lex_identifier_or_boolean(),
                 _ => {
                     self.advance();
-                    Token::new(TokenType::Unknown, c.to_string(), self.start_index, self.current_index)
+                    return Err(LexError::UnexpectedCharacter {
+                        found: c,
+                        span: Span::new(self.start_index, self.current_index),
+                    });
                 }
             };
             tokens.push(token);
         }
+        Ok(())
+    }
 
-        tokens.push(Token::new(TokenType::EOF, "".to_string(), self.current_index, self.current_index));
-        tokens
+    fn peek(&self) -> Option<char> {
+        self.input.clone().next()
     }
 
-    fn lex_number(&mut self) -> Token {
-        let mut number = self.capture(|c| c.is_digit(10));
-        if let Some('.') = self.current_char {
-            self.advance();
-            number.push('.');
-            number.push_str(&self.capture(|c| c.is_digit(10)));
-            Token::new(TokenType::FloatLiteral, number, self.start_index, self.current_index)
+    /*
+     * 42, 1_000_000, 2.5, 5., .5, 1e-9, 0xFF, 0b1010, 0o17. the text is kept as
+     * written, Parser::primary works out the value
+     */
+    fn lex_number(&mut self) -> Result<Token, LexError> {
+        let mut number = String::new();
+        let radix = match (self.current_char, self.peek()) {
+            (Some('0'), Some('x' | 'X')) => 16,
+            (Some('0'), Some('b' | 'B')) => 2,
+            (Some('0'), Some('o' | 'O')) => 8,
+            _ => 10,
+        };
+        let mut float = false;
+        if radix != 10 {
+            for _ in 0..2 {
+                number.push(self.current_char.unwrap());
+                self.advance();
+            }
+            self.lex_digits(radix, &mut number)?;
         } else {
-            Token::new(TokenType::IntegerLiteral, number, self.start_index, self.current_index)
+            if self.current_char != Some('.') {
+                self.lex_digits(10, &mut number)?;
+            }
+            if self.current_char == Some('.') {
+                float = true;
+                number.push('.');
+                self.advance();
+                if matches!(self.current_char, Some('0'..='9')) {
+                    self.lex_digits(10, &mut number)?;
+                }
+            }
+            if let Some(e @ ('e' | 'E')) = self.current_char {
+                float = true;
+                number.push(e);
+                self.advance();
+                if let Some(sign @ ('+' | '-')) = self.current_char {
+                    number.push(sign);
+                    self.advance();
+                }
+                self.lex_digits(10, &mut number)?;
+            }
+        }
+        /* 0b12 or 7abc would otherwise split into two tokens */
+        if matches!(self.current_char, Some(c) if c.is_alphanumeric() || c == '_' || c == '.') {
+            number.push_str(&self.capture(|c| c.is_alphanumeric() || c == '_' || c == '.'));
+            return Err(self.invalid_number(number));
+        }
+        let token_type = if float { TokenType::FloatLiteral } else { TokenType::IntegerLiteral };
+        Ok(Token::new(token_type, number, self.start_index, self.current_index))
+    }
+
+    /* one or more digits, an _ only ever between two of them */
+    fn lex_digits(&mut self, radix: u32, number: &mut String) -> Result<(), LexError> {
+        let digits = self.capture(|c| c.is_digit(radix) || c == '_');
+        number.push_str(&digits);
+        if digits.is_empty() || digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+            number.push_str(&self.capture(|c| c.is_alphanumeric() || c == '_'));
+            return Err(self.invalid_number(number.clone()));
         }
+        Ok(())
     }
 
-    fn lex_string(&mut self) -> Token {
+    fn invalid_number(&self, text: String) -> LexError {
+        LexError::InvalidNumber { text, span: Span::new(self.start_index, self.current_index) }
+    }
+
+    /*
+     * "a${x}b" comes out as the tokens of ("a" ++ (x) ++ "b"), so the parser
+     * only ever sees plain string literals. escapes are \" \\ \n \t \$ and \u{..}
+     */
+    fn lex_string(&mut self, tokens: &mut Vec<Token>) -> Result<(), LexError> {
+        let start = self.start_index;
         self.advance();
-        let content = self.capture(|c| c != '"');
-        if self.current_char == Some('"') {
+        let mut templated = false;
+        let mut content = String::new();
+        let mut segment_start = start;
+        loop {
+            match self.current_char {
+                None => return Err(LexError::UnterminatedString { span: Span::new(start, self.current_index) }),
+                Some('"') => break,
+                Some('\\') => content.push(self.lex_escape(start)?),
+                Some('$') => {
+                    let dollar = self.current_index;
+                    self.advance();
+                    if self.current_char != Some('{') {
+                        content.push('$');
+                        continue;
+                    }
+                    self.advance();
+                    /* the leading text always stays so the result is a string, empty text in between goes */
+                    if !templated {
+                        tokens.push(Token::new(TokenType::ParenOpen, "(".to_string(), start, start + 1));
+                        tokens.push(Token::new(TokenType::StringLiteral, std::mem::take(&mut content), segment_start, dollar));
+                        templated = true;
+                    } else if !content.is_empty() {
+                        tokens.push(Token::new(TokenType::BinaryOp("++".to_string()), "++".to_string(), segment_start, segment_start));
+                        tokens.push(Token::new(TokenType::StringLiteral, std::mem::take(&mut content), segment_start, dollar));
+                    }
+                    tokens.push(Token::new(TokenType::BinaryOp("++".to_string()), "++".to_string(), dollar, self.current_index));
+                    tokens.push(Token::new(TokenType::ParenOpen, "(".to_string(), dollar, self.current_index));
+                    /* a string left open inside the braces leaves this one open too */
+                    self.lex_tokens(tokens).map_err(|e| match e {
+                        LexError::UnterminatedString { .. } => LexError::UnterminatedString { span: Span::new(start, self.current_index) },
+                        e => e,
+                    })?;
+                    if self.current_char != Some('}') {
+                        return Err(LexError::UnterminatedString { span: Span::new(start, self.current_index) });
+                    }
+                    self.start_index = self.current_index;
+                    self.advance();
+                    tokens.push(Token::new(TokenType::ParenClose, ")".to_string(), self.start_index, self.current_index));
+                    segment_start = self.current_index;
+                }
+                Some(c) => {
+                    content.push(c);
+                    self.advance();
+                }
+            }
+        }
+        self.advance();
+        if !templated {
+            tokens.push(Token::new(TokenType::StringLiteral, content, start, self.current_index));
+            return Ok(());
+        }
+        if !content.is_empty() {
+            tokens.push(Token::new(TokenType::BinaryOp("++".to_string()), "++".to_string(), segment_start, segment_start));
+            tokens.push(Token::new(TokenType::StringLiteral, content, segment_start, self.current_index - 1));
+        }
+        tokens.push(Token::new(TokenType::ParenClose, ")".to_string(), self.current_index - 1, self.current_index));
+        Ok(())
+    }
+
+    /* the character a backslash sequence stands for, the backslash is current_char */
+    fn lex_escape(&mut self, string_start: usize) -> Result<char, LexError> {
+        let start = self.current_index;
+        self.advance();
+        let escaped = match self.current_char {
+            Some('"') => Some('"'),
+            Some('\\') => Some('\\'),
+            Some('n') => Some('\n'),
+            Some('t') => Some('\t'),
+            Some('$') => Some('$'),
+            Some('u') => self.lex_unicode_escape(),
+            _ => None,
+        };
+        /* "abc\ or "\u{41 ran out of input, the string never closed */
+        if self.current_char.is_none() {
+            return Err(LexError::UnterminatedString { span: Span::new(string_start, self.current_index) });
+        }
+        /* the character that made it invalid is part of the error */
+        self.advance();
+        escaped.ok_or(LexError::InvalidEscape { span: Span::new(start, self.current_index) })
+    }
+
+    /* \u{1F600}, one to six hex digits naming a scalar value. stops on the } */
+    fn lex_unicode_escape(&mut self) -> Option<char> {
+        self.advance();
+        if self.current_char != Some('{') {
+            return None;
+        }
+        self.advance();
+        let code = self.capture(|c| c.is_ascii_hexdigit());
+        if self.current_char != Some('}') || code.is_empty() || code.len() > 6 {
+            return None;
+        }
+        char::from_u32(u32::from_str_radix(&code, 16).ok()?)
+    }
+
+    /* #REF!, #DIV/0!, #NAME?, the leading # is already consumed */
+    fn lex_error_literal(&mut self) -> Token {
+        let mut code = format!("#{}", self.capture(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '/'));
+        if let Some(c @ ('!' | '?')) = self.current_char {
             self.advance();
+            code.push(c);
         }
-        Token::new(TokenType::StringLiteral, content, self.start_index, self.current_index)
+        Token::new(TokenType::ErrorLiteral, code, self.start_index, self.current_index)
     }
 
+    /* a - with nothing before it to subtract from is a negation */
+    fn is_prefix(previous: Option<&Token>) -> bool {
+        match previous {
+            None => true,
+            Some(token) => matches!(
+                token.token_type,
+                TokenType::BinaryOp(_) | TokenType::UnaryOp(_) | TokenType::Eq | TokenType::ParenOpen | TokenType::BracketOpen | TokenType::Comma | TokenType::Colon
+            ),
+        }
+    }
+
+    /* && and || are logical, a single & or | is bitwise. ** is power, ^ stays xor. ++ joins strings */
     fn lex_binary_op(&mut self, op: char) -> Token {
         self.advance();
-        Token::new(TokenType::BinaryOp(op.to_string()), op.to_string(), self.start_index, self.current_index)
+        let mut text = op.to_string();
+        if matches!(op, '&' | '|' | '*' | '+') && self.current_char == Some(op) {
+            self.advance();
+            text.push(op);
+        }
+        Token::new(TokenType::BinaryOp(text.clone()), text, self.start_index, self.current_index)
     }
 
     fn lex_comparison(&mut self) -> Token {
@@ -200,15 +423,21 @@ impl<'a> Lexer<'a> {
         } else if first_char == '>' && self.current_char == Some('>') {
             self.advance();
             Token::new(TokenType::BinaryOp(">>".to_string()), ">>".to_string(), self.start_index, self.current_index)
+        } else if first_char == '!' {
+            Token::new(TokenType::UnaryOp("!".to_string()), "!".to_string(), self.start_index, self.current_index)
         } else {
             Token::new(TokenType::BinaryOp(first_char.to_string()), first_char.to_string(), self.start_index, self.current_index)
         }
     }
 
     fn lex_identifier_or_boolean(&mut self) -> Token {
-        let text = self.capture(|c| c.is_alphanumeric() || c == '_');
+        let text = self.capture(|c| c.is_alphanumeric() || c == '_' || c == '$');
+        let lower = text.to_ascii_lowercase();
         let token_type = match text.as_str() {
             "true" | "false" => TokenType::BooleanLiteral,
+            /* case-insensitive like the function names, INT(2.5) reads as a call */
+            _ if lower == "int" || lower == "float" => TokenType::UnaryOp(lower),
+            _ if A1::parse(&text).is_some() => TokenType::A1Reference,
             _ => TokenType::Identifier,
         };
         Token::new(token_type, text, self.start_index, self.current_index)
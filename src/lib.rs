@@ -0,0 +1,22 @@
+pub mod cell;
+pub mod convert;
+pub mod error;
+pub mod functions;
+pub mod graph;
+pub mod grid;
+pub mod lexer;
+pub mod number;
+pub mod parser;
+pub mod reference;
+pub mod text;
+pub mod tree;
+
+pub use cell::{Cell, CellValue, ErrorKind};
+pub use convert::Rounding;
+pub use functions::{Argument, Arity, UserFunction};
+pub use grid::Grid;
+pub use lexer::{Lexer, Token, TokenType};
+pub use number::OverflowPolicy;
+pub use parser::Parser;
+pub use reference::{Anchor, Notation};
+pub use tree::{Assignment, Expression, RangeValue};
@@ -1,14 +1,5 @@
-mod tree;
-mod grid;
-mod cell;
-mod lexer;
-mod parser;
-
-use grid::Grid;
-use tree::Expression;
-use cell::CellValue::Int;
-use lexer::{Lexer, Token, TokenType};
-use parser::Parser;
+use skytanic::CellValue::Int;
+use skytanic::{Anchor, Expression, Grid, Lexer, Parser};
 
 fn main() {
     let mut grid = Grid::new();
@@ -27,6 +18,7 @@ fn main() {
         Box::new(Expression::CellRValue(
             Box::new(Expression::Integer(100)),
             Box::new(Expression::Integer(1)),
+            Anchor::ABSOLUTE,
         )),
     );
     
@@ -50,7 +42,13 @@ fn main() {
 
     let mut lexer = Lexer::new(input);
 
-    let tokens = lexer.tokenize();
+    let tokens = match lexer.tokenize() {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            println!("Error lexing input: {}", e);
+            return;
+        }
+    };
     for token in &tokens {
         println!("{:?}", token);
     }
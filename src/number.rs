@@ -0,0 +1,156 @@
+use std::cmp::Ordering;
+
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
+
+use crate::cell::ErrorKind;
+use crate::error::EvalError;
+use crate::tree::Expression;
+
+/* what an integer result that does not fit an i64 turns into */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OverflowPolicy {
+    #[default]
+    Error, /* #NUM! */
+    Float,
+    BigInt,
+}
+
+/* past this a big integer is an overflow too, 2 ** 10 ** 12 would never finish */
+pub const MAX_BITS: u64 = 1 << 16;
+
+/*
+ * two numeric operands brought to the wider of their representations:
+ * Integer, then BigInteger, then Float. arithmetic, comparisons and the
+ * aggregates all promote through here.
+ */
+pub(crate) enum Operands {
+    Int(i64, i64),
+    Big(BigInt, BigInt),
+    Float(f64, f64),
+}
+
+impl Operands {
+    pub(crate) fn of(lhs: &Expression, rhs: &Expression) -> Option<Operands> {
+        match (lhs, rhs) {
+            (Expression::Integer(l), Expression::Integer(r)) => Some(Operands::Int(*l, *r)),
+            (Expression::Float(_), _) | (_, Expression::Float(_)) => {
+                Some(Operands::Float(to_float(lhs)?, to_float(rhs)?))
+            }
+            _ => Some(Operands::Big(to_big(lhs)?, to_big(rhs)?)),
+        }
+    }
+}
+
+/* lhs + rhs, None when either is not a number */
+pub(crate) fn add(
+    lhs: &Expression,
+    rhs: &Expression,
+    policy: OverflowPolicy,
+) -> Option<Result<Expression, EvalError>> {
+    Some(match Operands::of(lhs, rhs)? {
+        Operands::Int(l, r) => match l.checked_add(r) {
+            Some(sum) => Ok(Expression::Integer(sum)),
+            None => overflowed(policy, "addition", l as f64 + r as f64, || {
+                Some(BigInt::from(l) + r)
+            }),
+        },
+        Operands::Big(l, r) => big(l + r, "addition"),
+        Operands::Float(l, r) => Ok(Expression::Float(l + r)),
+    })
+}
+
+/* None only for a NaN, which is neither smaller, larger nor equal */
+pub(crate) fn ordering(operands: Operands) -> Option<Ordering> {
+    match operands {
+        Operands::Int(l, r) => Some(l.cmp(&r)),
+        Operands::Big(l, r) => Some(l.cmp(&r)),
+        Operands::Float(l, r) => l.partial_cmp(&r),
+    }
+}
+
+pub(crate) fn to_big(value: &Expression) -> Option<BigInt> {
+    match value {
+        Expression::Integer(i) => Some(BigInt::from(*i)),
+        Expression::BigInteger(b) => Some(b.clone()),
+        _ => None,
+    }
+}
+
+pub(crate) fn to_float(value: &Expression) -> Option<f64> {
+    match value {
+        Expression::Integer(i) => Some(*i as f64),
+        Expression::Float(f) => Some(*f),
+        Expression::BigInteger(b) => b.to_f64(),
+        _ => None,
+    }
+}
+
+/* a big integer result, back to a plain Integer whenever it fits */
+pub(crate) fn big(value: BigInt, op: &str) -> Result<Expression, EvalError> {
+    if value.bits() > MAX_BITS {
+        return Err(overflow(op));
+    }
+    match i64::try_from(&value) {
+        Ok(value) => Ok(Expression::Integer(value)),
+        Err(_) => Ok(Expression::BigInteger(value)),
+    }
+}
+
+/*
+ * an i64 operation that overflowed. float is the result as a Float, exact the
+ * precise one, None when it would be too large to build.
+ */
+pub(crate) fn overflowed(
+    policy: OverflowPolicy,
+    op: &str,
+    float: f64,
+    exact: impl FnOnce() -> Option<BigInt>,
+) -> Result<Expression, EvalError> {
+    match policy {
+        OverflowPolicy::Error => Err(overflow(op)),
+        OverflowPolicy::Float => Ok(Expression::Float(float)),
+        OverflowPolicy::BigInt => big(exact().ok_or_else(|| overflow(op))?, op),
+    }
+}
+
+pub(crate) fn overflow(op: &str) -> EvalError {
+    ErrorKind::Overflow(format!("Integer overflow in {}", op)).into()
+}
+
+/* 0, 1 and -1 come back to themselves or 1 whatever the exponent, keep it small */
+fn small_exponent(exponent: u64) -> u64 {
+    match exponent {
+        0..=2 => exponent,
+        _ => 2 - exponent % 2,
+    }
+}
+
+/* i64 power, None on overflow */
+pub(crate) fn checked_pow(base: i64, exponent: u64) -> Option<i64> {
+    let exponent = match base {
+        -1..=1 => small_exponent(exponent),
+        _ => exponent,
+    };
+    base.checked_pow(u32::try_from(exponent).ok()?)
+}
+
+/* base ** exponent, unless the result would have more than MAX_BITS bits */
+pub(crate) fn big_pow(base: &BigInt, exponent: u64) -> Option<BigInt> {
+    let exponent = match base.bits() {
+        0 | 1 => small_exponent(exponent),
+        _ => exponent,
+    };
+    if base.bits().saturating_mul(exponent) > MAX_BITS {
+        return None;
+    }
+    Some(base.pow(u32::try_from(exponent).ok()?))
+}
+
+/* value << amount, same limit */
+pub(crate) fn big_shl(value: &BigInt, amount: u64) -> Option<BigInt> {
+    if value.bits().saturating_add(amount) > MAX_BITS {
+        return None;
+    }
+    Some(value << usize::try_from(amount).ok()?)
+}
@@ -1,13 +1,41 @@
+use crate::cell::ErrorKind;
+use crate::error::{FormulaError, ParseError, Span};
+use crate::lexer::Lexer;
+use crate::reference::{Anchor, A1};
+use crate::tree::Assignment;
 use crate::Expression;
 use crate::TokenType;
 use crate::Token;
 
+/* the value of 42, 1_000, 0xFF, 0b1010 or 0o17, None past u64 */
+fn integer_literal(text: &str) -> Option<u64> {
+    let digits = text.replace('_', "");
+    match digits.get(..2) {
+        Some("0x" | "0X") => u64::from_str_radix(&digits[2..], 16).ok(),
+        Some("0b" | "0B") => u64::from_str_radix(&digits[2..], 2).ok(),
+        Some("0o" | "0O") => u64::from_str_radix(&digits[2..], 8).ok(),
+        _ => digits.parse().ok(),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Parser {
     tokens: Vec<Token>,
     current_index: usize,
 }
 
+/* lex and parse formula text in one step */
+pub fn parse_formula(source: &str) -> Result<Expression, FormulaError> {
+    let tokens = Lexer::new(source).tokenize()?;
+    Ok(Parser::new(tokens).parse()?)
+}
+
+/* one script statement, [col, row] = expression */
+pub fn parse_statement(source: &str) -> Result<Assignment, FormulaError> {
+    let tokens = Lexer::new(source).tokenize()?;
+    Ok(Parser::new(tokens).parse_assignment()?)
+}
+
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Self {
         Parser {
@@ -16,92 +44,166 @@ impl Parser {
         }
     }
 
-    pub fn parse(&mut self) -> Result<Expression, String> {
-        self.expression()
+    pub fn parse(&mut self) -> Result<Expression, ParseError> {
+        let expr = self.expression()?;
+        if !self.has(TokenType::EOF) {
+            return Err(self.unexpected());
+        }
+        Ok(expr)
+    }
+
+    pub fn parse_assignment(&mut self) -> Result<Assignment, ParseError> {
+        if !self.has(TokenType::BracketOpen) {
+            return Err(self.expected("'[' starting the cell to assign"));
+        }
+        let target = self.primary()?;
+        self.expect(TokenType::Eq, "'=' after the cell to assign")?;
+        let value = self.parse()?;
+        Ok(Assignment { target, value })
     }
 
-    fn expression(&mut self) -> Result<Expression, String> {
+    fn expression(&mut self) -> Result<Expression, ParseError> {
         self.logical_or()
     }
 
-    fn logical_or(&mut self) -> Result<Expression, String> {
+    fn logical_or(&mut self) -> Result<Expression, ParseError> {
+        let start = self.start();
         let mut left = self.logical_and()?;
 
         while self.has(TokenType::BinaryOp("||".to_string())) {
             self.advance();
             let right = self.logical_and()?;
-            left = Expression::LOr(Box::new(left), Box::new(right));
+            left = self.spanned(start, Expression::LOr(Box::new(left), Box::new(right)));
         }
 
         Ok(left)
     }
 
-    fn logical_and(&mut self) -> Result<Expression, String> {
+    fn logical_and(&mut self) -> Result<Expression, ParseError> {
+        let start = self.start();
         let mut left = self.bitwise_or()?;
 
         while self.has(TokenType::BinaryOp("&&".to_string())) {
             self.advance();
             let right = self.bitwise_or()?;
-            left = Expression::LAnd(Box::new(left), Box::new(right));
+            left = self.spanned(start, Expression::LAnd(Box::new(left), Box::new(right)));
         }
 
         Ok(left)
     }
 
-    fn bitwise_or(&mut self) -> Result<Expression, String> {
+    fn bitwise_or(&mut self) -> Result<Expression, ParseError> {
+        let start = self.start();
         let mut left = self.bitwise_xor()?;
 
         while self.has(TokenType::BinaryOp("|".to_string())) {
             self.advance();
             let right = self.bitwise_xor()?;
-            left = Expression::BOr(Box::new(left), Box::new(right));
+            left = self.spanned(start, Expression::BOr(Box::new(left), Box::new(right)));
         }
 
         Ok(left)
     }
 
-    fn bitwise_xor(&mut self) -> Result<Expression, String> {
+    fn bitwise_xor(&mut self) -> Result<Expression, ParseError> {
+        let start = self.start();
         let mut left = self.bitwise_and()?;
 
         while self.has(TokenType::BinaryOp("^".to_string())) {
             self.advance();
             let right = self.bitwise_and()?;
-            left = Expression::Xor(Box::new(left), Box::new(right));
+            left = self.spanned(start, Expression::Xor(Box::new(left), Box::new(right)));
         }
 
         Ok(left)
     }
 
-    fn bitwise_and(&mut self) -> Result<Expression, String> {
-        let mut left = self.shift()?;
+    fn bitwise_and(&mut self) -> Result<Expression, ParseError> {
+        let start = self.start();
+        let mut left = self.equality()?;
 
         while self.has(TokenType::BinaryOp("&".to_string())) {
+            self.advance();
+            let right = self.equality()?;
+            left = self.spanned(start, Expression::BAnd(Box::new(left), Box::new(right)));
+        }
+
+        Ok(left)
+    }
+
+    fn equality(&mut self) -> Result<Expression, ParseError> {
+        let start = self.start();
+        let mut left = self.relational()?;
+
+        while self.has(TokenType::BinaryOp("==".to_string())) || self.has(TokenType::BinaryOp("!=".to_string())) || self.has(TokenType::BinaryOp("~=".to_string())) {
+            let op_token = self.tokens[self.current_index].clone();
+            self.advance();
+            let right = self.relational()?;
+            let (lhs, rhs) = (Box::new(left), Box::new(right));
+            left = match op_token.text.as_str() {
+                "==" => self.spanned(start, Expression::Equals(lhs, rhs)),
+                "!=" => self.spanned(start, Expression::NotEquals(lhs, rhs)),
+                _ => self.spanned(start, Expression::EqualsIgnoreCase(lhs, rhs)),
+            };
+        }
+
+        Ok(left)
+    }
+
+    fn relational(&mut self) -> Result<Expression, ParseError> {
+        let start = self.start();
+        let mut left = self.shift()?;
+
+        while self.has(TokenType::BinaryOp("<".to_string())) || self.has(TokenType::BinaryOp("<=".to_string())) || self.has(TokenType::BinaryOp(">".to_string())) || self.has(TokenType::BinaryOp(">=".to_string())) {
+            let op_token = self.tokens[self.current_index].clone();
             self.advance();
             let right = self.shift()?;
-            left = Expression::BAnd(Box::new(left), Box::new(right));
+            let (lhs, rhs) = (Box::new(left), Box::new(right));
+            left = match op_token.text.as_str() {
+                "<" => self.spanned(start, Expression::LessThan(lhs, rhs)),
+                "<=" => self.spanned(start, Expression::LessThanEq(lhs, rhs)),
+                ">" => self.spanned(start, Expression::GreaterThan(lhs, rhs)),
+                _ => self.spanned(start, Expression::GreaterThanEq(lhs, rhs)),
+            };
         }
 
         Ok(left)
     }
 
-    fn shift(&mut self) -> Result<Expression, String> {
-        let mut left = self.additive()?;
+    fn shift(&mut self) -> Result<Expression, ParseError> {
+        let start = self.start();
+        let mut left = self.concatenation()?;
 
         while self.has(TokenType::BinaryOp("<<".to_string())) || self.has(TokenType::BinaryOp(">>".to_string())) {
             let op_token = self.tokens[self.current_index].clone();
             self.advance();
-            let right = self.additive()?;
+            let right = self.concatenation()?;
             if op_token.text == "<<" {
-                left = Expression::LeftShift(Box::new(left), Box::new(right));
+                left = self.spanned(start, Expression::LeftShift(Box::new(left), Box::new(right)));
             } else {
-                left = Expression::RightShift(Box::new(left), Box::new(right));
+                left = self.spanned(start, Expression::RightShift(Box::new(left), Box::new(right)));
             }
         }
 
         Ok(left)
     }
 
-    fn additive(&mut self) -> Result<Expression, String> {
+    /* below + and -, so "total: " ++ A1 + A2 joins the sum */
+    fn concatenation(&mut self) -> Result<Expression, ParseError> {
+        let start = self.start();
+        let mut left = self.additive()?;
+
+        while self.has(TokenType::BinaryOp("++".to_string())) {
+            self.advance();
+            let right = self.additive()?;
+            left = self.spanned(start, Expression::Concat(Box::new(left), Box::new(right)));
+        }
+
+        Ok(left)
+    }
+
+    fn additive(&mut self) -> Result<Expression, ParseError> {
+        let start = self.start();
         let mut left = self.multiplicative()?;
 
         while self.has(TokenType::BinaryOp("+".to_string())) || self.has(TokenType::BinaryOp("-".to_string())) {
@@ -109,136 +211,204 @@ impl Parser {
             self.advance();
             let right = self.multiplicative()?;
             if op_token.text == "+" {
-                left = Expression::Add(Box::new(left), Box::new(right));
+                left = self.spanned(start, Expression::Add(Box::new(left), Box::new(right)));
             } else {
-                left = Expression::Subtract(Box::new(left), Box::new(right));
+                left = self.spanned(start, Expression::Subtract(Box::new(left), Box::new(right)));
             }
         }
 
         Ok(left)
     }
 
-    fn multiplicative(&mut self) -> Result<Expression, String> {
-        let mut left = self.exponentiation()?;
+    fn multiplicative(&mut self) -> Result<Expression, ParseError> {
+        let start = self.start();
+        let mut left = self.unary()?;
 
         while self.has(TokenType::BinaryOp("*".to_string())) || self.has(TokenType::BinaryOp("/".to_string())) || self.has(TokenType::BinaryOp("%".to_string())) {
             let op_token = self.tokens[self.current_index].clone();
             self.advance();
-            let right = self.exponentiation()?;
+            let right = self.unary()?;
             if op_token.text == "*" {
-                left = Expression::Multiply(Box::new(left), Box::new(right));
+                left = self.spanned(start, Expression::Multiply(Box::new(left), Box::new(right)));
             } else if op_token.text == "/" {
-                left = Expression::Divide(Box::new(left), Box::new(right));
+                left = self.spanned(start, Expression::Divide(Box::new(left), Box::new(right)));
             } else {
-                left = Expression::Modulo(Box::new(left), Box::new(right));
+                left = self.spanned(start, Expression::Modulo(Box::new(left), Box::new(right)));
             }
         }
 
         Ok(left)
     }
 
-    fn exponentiation(&mut self) -> Result<Expression, String> {
-        let mut left = self.unary()?;
+    /* right associative and tighter than a prefix -, so -2 ** 2 is -4 and 2 ** 3 ** 2 is 2 ** 9 */
+    fn exponentiation(&mut self) -> Result<Expression, ParseError> {
+        let start = self.start();
+        let base = self.primary()?;
 
-        while self.has(TokenType::BinaryOp("^".to_string())) {
+        if self.has(TokenType::BinaryOp("**".to_string())) {
             self.advance();
-            let right = self.unary()?;
-            left = Expression::Exp(Box::new(left), Box::new(right));
+            let exponent = self.unary()?;
+            return Ok(self.spanned(start, Expression::Exp(Box::new(base), Box::new(exponent))));
         }
 
-        Ok(left)
+        Ok(base)
     }
 
-    fn unary(&mut self) -> Result<Expression, String> {
+    fn unary(&mut self) -> Result<Expression, ParseError> {
+        let start = self.start();
+        /* i64::MIN only fits once negated, unless ** takes the literal first */
+        let after = self.tokens.get(self.current_index + 2).map(|token| token.token_type.clone());
+        if self.has(TokenType::UnaryOp("-".to_string())) && self.next_is(TokenType::IntegerLiteral) && after != Some(TokenType::BinaryOp("**".to_string()))
+            && integer_literal(&self.tokens[self.current_index + 1].text) == Some(i64::MIN.unsigned_abs())
+        {
+            self.advance();
+            self.advance();
+            return Ok(self.spanned(start, Expression::Integer(i64::MIN)));
+        }
         if self.has(TokenType::UnaryOp("-".to_string())) {
             self.advance();
             let expr = self.unary()?;
-            return Ok(Expression::Negate(Box::new(expr)));
+            return Ok(self.spanned(start, Expression::Negate(Box::new(expr))));
         }
         if self.has(TokenType::UnaryOp("~".to_string())) {
             self.advance();
             let expr = self.unary()?;
-            return Ok(Expression::BNot(Box::new(expr)));
+            return Ok(self.spanned(start, Expression::BNot(Box::new(expr))));
         }
         if self.has(TokenType::UnaryOp("!".to_string())) {
             self.advance();
             let expr = self.unary()?;
-            return Ok(Expression::LNot(Box::new(expr)));
+            return Ok(self.spanned(start, Expression::LNot(Box::new(expr))));
         }
         if self.has(TokenType::UnaryOp("int".to_string())) {
             self.advance();
             let expr = self.unary()?;
-            return Ok(Expression::FTI(Box::new(expr)));
+            return Ok(self.spanned(start, Expression::ToInt(Box::new(expr))));
         }
         if self.has(TokenType::UnaryOp("float".to_string())) {
             self.advance();
             let expr = self.unary()?;
-            return Ok(Expression::ITF(Box::new(expr)));
+            return Ok(self.spanned(start, Expression::ToFloat(Box::new(expr))));
         }
-        self.primary()
+        self.exponentiation()
     }
 
-    fn primary(&mut self) -> Result<Expression, String> {
+    fn primary(&mut self) -> Result<Expression, ParseError> {
+        let start = self.start();
         if self.has(TokenType::IntegerLiteral) {
             let token = self.tokens[self.current_index].clone();
+            let value = integer_literal(&token.text).and_then(|value| i64::try_from(value).ok());
+            let value = value.ok_or_else(|| ParseError::OutOfRange { found: token.text.clone(), span: token.span() })?;
             self.advance();
-            return Ok(Expression::Integer(token.text.parse().unwrap()));
+            return Ok(Expression::Integer(value));
         }
         if self.has(TokenType::FloatLiteral) {
             let token = self.tokens[self.current_index].clone();
+            let value = match token.text.replace('_', "").parse::<f64>() {
+                Ok(value) if value.is_finite() => value,
+                _ => return Err(ParseError::OutOfRange { found: token.text.clone(), span: token.span() }),
+            };
             self.advance();
-            return Ok(Expression::Float(token.text.parse().unwrap()));
+            return Ok(Expression::Float(value));
         }
         if self.has(TokenType::BooleanLiteral) {
             let token = self.tokens[self.current_index].clone();
             self.advance();
             return Ok(Expression::Boolean(token.text == "true"));
         }
+        /* #REF! and friends, written back by a fill that pushed a reference off the sheet */
+        if self.has(TokenType::ErrorLiteral) {
+            let kind = match ErrorKind::from_code(&self.current().text) {
+                Some(kind) => kind,
+                None => return Err(self.unexpected()),
+            };
+            self.advance();
+            return Ok(Expression::Error(kind));
+        }
         if self.has(TokenType::StringLiteral) {
             let token = self.tokens[self.current_index].clone();
             self.advance();
             return Ok(Expression::String(token.text));
         }
-        if self.has(TokenType::ParenOpen) {
+        /* log10(x) reads like a cell name, a following paren makes it a call */
+        let is_call = self.has(TokenType::A1Reference) && self.next_is(TokenType::ParenOpen);
+        if self.has(TokenType::Identifier) || is_call {
+            let name = self.current().text.clone();
             self.advance();
-            let expr = self.expression()?;
-            if !self.has(TokenType::ParenClose) {
-                return Err("Expected closing parenthesis".to_string());
+            if !self.has(TokenType::ParenOpen) {
+                return Err(self.expected("'(' after function name"));
             }
+            let args = self.arguments()?;
+            return Ok(self.spanned(start, Expression::Call(name, args)));
+        }
+        if self.has(TokenType::ParenOpen) {
             self.advance();
+            let expr = self.expression()?;
+            self.expect(TokenType::ParenClose, "closing parenthesis")?;
             return Ok(expr);
         }
-        if self.has(TokenType::CellReference) {
-            self.advance();
-            if !self.has(TokenType::BracketOpen) {
-                return Err("Expected opening bracket".to_string());
+        if self.has(TokenType::CellReference) || self.has(TokenType::A1Reference) {
+            let reference = self.cell_reference()?;
+            if self.has(TokenType::Colon) {
+                self.advance();
+                let end = self.cell_reference()?;
+                return Ok(self.spanned(start, Expression::Range(Box::new(reference), Box::new(end))));
             }
+            return Ok(reference);
+        }
+        if self.has(TokenType::BracketOpen) {
             self.advance();
             let col = self.expression()?;
-            self.advance(); /* comma */
+            self.expect(TokenType::Comma, "comma")?;
             let row = self.expression()?;
-            if !self.has(TokenType::BracketClose) {
-                return Err("Expected closing bracket".to_string());
-            }
+            self.expect(TokenType::BracketClose, "closing bracket")?;
+            return Ok(self.spanned(start, Expression::CellLValue(Box::new(col), Box::new(row))));
+        }
+        if self.has(TokenType::EOF) {
+            return Err(ParseError::UnexpectedEnd {
+                expected: "an expression",
+                span: self.current().span(),
+            });
+        }
+        Err(self.unexpected())
+    }
+
+    /* #[col, row] or B7 */
+    fn cell_reference(&mut self) -> Result<Expression, ParseError> {
+        let start = self.start();
+        if self.has(TokenType::A1Reference) {
+            let a1 = A1::parse(&self.current().text).unwrap();
             self.advance();
-            return Ok(Expression::CellRValue(Box::new(col), Box::new(row)));
+            let col = Expression::Integer(a1.col as i64);
+            let row = Expression::Integer(a1.row as i64);
+            return Ok(self.spanned(start, Expression::CellRValue(Box::new(col), Box::new(row), a1.anchor())));
         }
-        if self.has(TokenType::BracketOpen) {
+        self.expect(TokenType::CellReference, "cell reference")?;
+        self.expect(TokenType::BracketOpen, "opening bracket")?;
+        let col = self.expression()?;
+        self.expect(TokenType::Comma, "comma")?;
+        let row = self.expression()?;
+        self.expect(TokenType::BracketClose, "closing bracket")?;
+        Ok(self.spanned(start, Expression::CellRValue(Box::new(col), Box::new(row), Anchor::ABSOLUTE)))
+    }
+
+    /* ( [expression (, expression)*] ) */
+    fn arguments(&mut self) -> Result<Vec<Expression>, ParseError> {
+        self.expect(TokenType::ParenOpen, "opening parenthesis")?;
+        let mut args = Vec::new();
+        if self.has(TokenType::ParenClose) {
             self.advance();
-            let col = self.expression()?;
-            self.advance(); /* comma */
-            let row = self.expression()?;
-            if !self.has(TokenType::BracketClose) {
-                return Err("Expected closing bracket".to_string());
+            return Ok(args);
+        }
+        loop {
+            args.push(self.expression()?);
+            if self.has(TokenType::Comma) {
+                self.advance();
+                continue;
             }
-            self.advance();
-            return Ok(Expression::CellLValue(Box::new(col), Box::new(row)));
+            self.expect(TokenType::ParenClose, "closing parenthesis")?;
+            return Ok(args);
         }
-        Err(format!(
-            "Unexpected token: {:?} at position {}",
-            self.tokens[self.current_index],
-            self.current_index
-        ))
     }
 
     fn has(&mut self, token_type: TokenType) -> bool {
@@ -254,4 +424,54 @@ impl Parser {
             self.current_index += 1;
         }
     }
+
+    fn next_is(&self, token_type: TokenType) -> bool {
+        self.tokens.get(self.current_index + 1).is_some_and(|token| token.token_type == token_type)
+    }
+
+    /* the token under the cursor, the trailing EOF once we run past the end */
+    fn current(&self) -> &Token {
+        let last = self.tokens.len().saturating_sub(1);
+        &self.tokens[self.current_index.min(last)]
+    }
+
+    fn expect(&mut self, token_type: TokenType, expected: &'static str) -> Result<Token, ParseError> {
+        if self.has(token_type) {
+            let token = self.current().clone();
+            self.advance();
+            return Ok(token);
+        }
+        Err(self.expected(expected))
+    }
+
+    fn expected(&self, expected: &'static str) -> ParseError {
+        let token = self.current();
+        if token.token_type == TokenType::EOF {
+            ParseError::UnexpectedEnd { expected, span: token.span() }
+        } else {
+            ParseError::Expected { expected, found: token.text.clone(), span: token.span() }
+        }
+    }
+
+    fn unexpected(&self) -> ParseError {
+        let token = self.current();
+        ParseError::UnexpectedToken {
+            found: token.text.clone(),
+            span: token.span(),
+        }
+    }
+
+    /* byte offset where the next node starts */
+    fn start(&self) -> usize {
+        self.current().start_index
+    }
+
+    /* wrap a node with the source it was parsed from, up to the last token consumed */
+    fn spanned(&self, start: usize, expr: Expression) -> Expression {
+        let end = match self.current_index {
+            0 => start,
+            index => self.tokens[index - 1].end_index,
+        };
+        Expression::Spanned(Box::new(expr), Span::new(start, end))
+    }
 }
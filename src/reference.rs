@@ -0,0 +1,221 @@
+use std::fmt;
+
+/* how cell references are written back out by Expression::serialize_with */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Notation {
+    Index, /* #[col, row], zero based */
+    A1,    /* B7, $C$3, AA100 */
+}
+
+/*
+ * which axes of a reference stay put when its formula is copied. $B7 pins the
+ * column, B$7 the row. #[col, row] references are always absolute.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Anchor {
+    pub col_absolute: bool,
+    pub row_absolute: bool,
+}
+
+impl Anchor {
+    pub const RELATIVE: Anchor = Anchor {
+        col_absolute: false,
+        row_absolute: false,
+    };
+    pub const ABSOLUTE: Anchor = Anchor {
+        col_absolute: true,
+        row_absolute: true,
+    };
+}
+
+/* an A1 style reference, col and row zero based like #[col, row] */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct A1 {
+    pub col: usize,
+    pub row: usize,
+    pub col_absolute: bool,
+    pub row_absolute: bool,
+}
+
+impl A1 {
+    pub fn relative(row: usize, col: usize) -> A1 {
+        A1 {
+            col,
+            row,
+            col_absolute: false,
+            row_absolute: false,
+        }
+    }
+
+    pub fn anchored(row: usize, col: usize, anchor: Anchor) -> A1 {
+        A1 {
+            col,
+            row,
+            col_absolute: anchor.col_absolute,
+            row_absolute: anchor.row_absolute,
+        }
+    }
+
+    pub fn anchor(&self) -> Anchor {
+        Anchor {
+            col_absolute: self.col_absolute,
+            row_absolute: self.row_absolute,
+        }
+    }
+
+    /* B7, $B7, B$7, $B$7, column letters are case-insensitive */
+    pub fn parse(text: &str) -> Option<A1> {
+        let mut rest = text;
+        let col_absolute = rest.starts_with('$');
+        if col_absolute {
+            rest = &rest[1..];
+        }
+
+        let letters = rest.chars().take_while(|c| c.is_ascii_alphabetic()).count();
+        if letters == 0 {
+            return None;
+        }
+        let mut col: usize = 0;
+        for c in rest[..letters].chars() {
+            let digit = (c.to_ascii_uppercase() as u8 - b'A') as usize + 1;
+            col = col.checked_mul(26)?.checked_add(digit)?;
+        }
+        rest = &rest[letters..];
+
+        let row_absolute = rest.starts_with('$');
+        if row_absolute {
+            rest = &rest[1..];
+        }
+        if rest.is_empty() || !rest.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let row: usize = rest.parse().ok()?;
+        /* coordinates have to fit the i64 literals of a CellRValue */
+        if row == 0 || row > i64::MAX as usize || col > i64::MAX as usize {
+            return None;
+        }
+
+        Some(A1 {
+            col: col - 1,
+            row: row - 1,
+            col_absolute,
+            row_absolute,
+        })
+    }
+}
+
+impl fmt::Display for A1 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}{}{}{}",
+            if self.col_absolute { "$" } else { "" },
+            column_name(self.col),
+            if self.row_absolute { "$" } else { "" },
+            self.row + 1
+        )
+    }
+}
+
+/* 0 -> A, 25 -> Z, 26 -> AA */
+pub fn column_name(col: usize) -> String {
+    let mut name = Vec::new();
+    let mut n = col + 1;
+    while n > 0 {
+        let rem = (n - 1) % 26;
+        name.push(b'A' + rem as u8);
+        n = (n - 1) / 26;
+    }
+    name.reverse();
+    String::from_utf8(name).unwrap()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Axis {
+    Row,
+    Col,
+}
+
+/* a layout change, references are rewritten so they follow their data */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SheetEdit {
+    Insert { axis: Axis, at: usize, count: usize },
+    Delete { axis: Axis, at: usize, count: usize },
+}
+
+impl SheetEdit {
+    pub fn axis(&self) -> Axis {
+        match *self {
+            SheetEdit::Insert { axis, .. } | SheetEdit::Delete { axis, .. } => axis,
+        }
+    }
+
+    /* where an index along the edited axis ends up, None if it was deleted */
+    pub fn index(&self, index: usize) -> Option<usize> {
+        match *self {
+            /* past i64::MAX a CellRValue can no longer hold it */
+            SheetEdit::Insert { at, count, .. } if index >= at => index
+                .checked_add(count)
+                .filter(|index| *index <= i64::MAX as usize),
+            SheetEdit::Delete { at, count, .. } if index >= at => {
+                if index - at < count {
+                    None
+                } else {
+                    Some(index - count)
+                }
+            }
+            _ => Some(index),
+        }
+    }
+
+    /* a cell's new (row, col), None if it was deleted */
+    pub fn cell(&self, (row, col): (usize, usize)) -> Option<(usize, usize)> {
+        match self.axis() {
+            Axis::Row => Some((self.index(row)?, col)),
+            Axis::Col => Some((row, self.index(col)?)),
+        }
+    }
+
+    /* new first and last index of a span, deleting part of it shrinks it */
+    pub fn span(&self, first: usize, last: usize) -> Option<(usize, usize)> {
+        let (at, count) = match *self {
+            SheetEdit::Insert { .. } => return Some((self.index(first)?, self.index(last)?)),
+            SheetEdit::Delete { at, count, .. } => (at, count),
+        };
+        let new_first = match self.index(first) {
+            Some(index) => index,
+            None if last - at >= count => at,
+            None => return None,
+        };
+        let new_last = match self.index(last) {
+            Some(index) => index,
+            None if first < at => at - 1,
+            None => return None,
+        };
+        Some((new_first, new_last))
+    }
+
+    /* new corners of a rectangle, None once all of it was deleted */
+    pub fn range(
+        &self,
+        start: (usize, usize),
+        end: (usize, usize),
+    ) -> Option<((usize, usize), (usize, usize))> {
+        let ((r1, c1), (r2, c2)) = (start, end);
+        /* corners can be given in either order, keep it */
+        let along = |a: usize, b: usize| {
+            let (first, last) = self.span(a.min(b), a.max(b))?;
+            Some(if a <= b { (first, last) } else { (last, first) })
+        };
+        match self.axis() {
+            Axis::Row => {
+                let (r1, r2) = along(r1, r2)?;
+                Some(((r1, c1), (r2, c2)))
+            }
+            Axis::Col => {
+                let (c1, c2) = along(c1, c2)?;
+                Some(((r1, c1), (r2, c2)))
+            }
+        }
+    }
+}
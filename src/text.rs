@@ -0,0 +1,59 @@
+use crate::cell::ErrorKind;
+use crate::convert;
+use crate::error::EvalError;
+use crate::tree::{mismatch, Expression};
+
+/* longest string a formula may build, in bytes */
+pub const MAX_LEN: usize = 1 << 24;
+
+/* both sides as the text their cells would show, so numbers join too */
+pub(crate) fn concat(lhs: &Expression, rhs: &Expression) -> Result<Expression, EvalError> {
+    match (convert::text(lhs), convert::text(rhs)) {
+        (Ok(l), Ok(r)) => {
+            if l.len() + r.len() > MAX_LEN {
+                return Err(too_long("concatenation"));
+            }
+            Ok(Expression::String(l + &r))
+        }
+        _ => Err(mismatch("Incompatible types for concatenation")),
+    }
+}
+
+/* "ab" * 3 and 3 * "ab" are both "ababab" */
+pub(crate) fn repeat(text: &str, count: i64) -> Result<Expression, EvalError> {
+    let count =
+        usize::try_from(count).map_err(|_| mismatch("Repeat count must not be negative"))?;
+    match text.len().checked_mul(count) {
+        Some(len) if len <= MAX_LEN => Ok(Expression::String(text.repeat(count))),
+        _ => Err(too_long("repetition")),
+    }
+}
+
+/* a string literal that lexes back to text */
+pub fn quote(text: &str) -> String {
+    let mut quoted = String::from('"');
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            /* a bare $ only starts a template in front of { */
+            '$' if chars.peek() == Some(&'{') => quoted.push_str("\\$"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/* unicode aware, "STRASSE" and "strasse" match but "straße" does not */
+pub(crate) fn equal_ignoring_case(lhs: &str, rhs: &str) -> bool {
+    lhs == rhs || lhs.to_lowercase() == rhs.to_lowercase()
+}
+
+fn too_long(op: &str) -> EvalError {
+    ErrorKind::Overflow(format!("String too long in {}", op)).into()
+}
@@ -1,12 +1,41 @@
-use crate::cell::CellValue;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+
+use num_bigint::{BigInt, Sign};
+use num_traits::{ToPrimitive, Zero};
+
+use crate::cell::{CellValue, ErrorKind};
+use crate::convert::{self, Rounding};
+use crate::error::{EvalError, Span};
+use crate::functions;
+use crate::number::{self, Operands};
+use crate::reference::{Anchor, Notation, SheetEdit, A1};
+use crate::text;
 use crate::Grid;
 
+/* a range's corners and the occupied cells inside it, row by row */
+#[derive(Debug, Clone)]
+pub struct RangeValue {
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+    pub cells: Vec<((usize, usize), Expression)>,
+}
+
+impl RangeValue {
+    pub fn values(&self) -> impl Iterator<Item = &Expression> {
+        self.cells.iter().map(|(_, value)| value)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Expression {
+    Empty, /* value of a blank cell */
     Integer(i64),
+    BigInteger(BigInt), /* only ever outside the i64 range */
     Float(f64),
     Boolean(bool),
     String(String),
+    Error(ErrorKind), /* #REF! left behind by a reference that moved off the sheet */
 
     Add(Box<Expression>, Box<Expression>),
     Subtract(Box<Expression>, Box<Expression>),
@@ -15,13 +44,16 @@ pub enum Expression {
     Modulo(Box<Expression>, Box<Expression>),
     Exp(Box<Expression>, Box<Expression>),
     Negate(Box<Expression>),
+    Concat(Box<Expression>, Box<Expression>), /* a ++ b */
 
     LAnd(Box<Expression>, Box<Expression>),
     LOr(Box<Expression>, Box<Expression>),
     LNot(Box<Expression>),
 
     CellLValue(Box<Expression>, Box<Expression>),
-    CellRValue(Box<Expression>, Box<Expression>),
+    CellRValue(Box<Expression>, Box<Expression>, Anchor),
+    Range(Box<Expression>, Box<Expression>), /* two CellRValue corners */
+    Array(RangeValue),                       /* value of a range */
 
     BAnd(Box<Expression>, Box<Expression>),
     BOr(Box<Expression>, Box<Expression>),
@@ -32,545 +64,1159 @@ pub enum Expression {
 
     Equals(Box<Expression>, Box<Expression>),
     NotEquals(Box<Expression>, Box<Expression>),
+    EqualsIgnoreCase(Box<Expression>, Box<Expression>), /* a ~= b */
     LessThan(Box<Expression>, Box<Expression>),
     LessThanEq(Box<Expression>, Box<Expression>),
     GreaterThan(Box<Expression>, Box<Expression>),
     GreaterThanEq(Box<Expression>, Box<Expression>),
 
-    FTI(Box<Expression>),
-    ITF(Box<Expression>),
+    ToInt(Box<Expression>),   /* int x, truncating */
+    ToFloat(Box<Expression>), /* float x */
 
     Max(Vec<Expression>),
     Min(Vec<Expression>),
     Mean(Vec<Expression>),
     Sum(Vec<Expression>),
+
+    /* name(args, ...), resolved against the grid's function registry */
+    Call(String, Vec<Expression>),
+
+    /* where a parsed node came from in the formula, evaluation errors inside pick it up */
+    Spanned(Box<Expression>, Span),
+}
+
+/* [col, row] = value, a script statement. cell formulas never assign */
+#[derive(Debug, Clone)]
+pub struct Assignment {
+    pub target: Expression, /* CellLValue */
+    pub value: Expression,
+}
+
+impl Assignment {
+    /* (row, col) being written, the coordinates can be any integer expression */
+    pub fn target(&self, env: &mut Grid) -> Result<(usize, usize), EvalError> {
+        let position = match self.target.unspanned() {
+            Expression::CellLValue(col, row) => {
+                let col = col.evaluate_operand(env)?;
+                let row = row.evaluate_operand(env)?;
+                Expression::cell_position(&col, &row)
+            }
+            _ => Err(mismatch("Only cells can be assigned to")),
+        };
+        match &self.target {
+            Expression::Spanned(_, span) => position.map_err(|e| e.or_span(*span)),
+            _ => position,
+        }
+    }
+
+    pub fn value(&self, env: &mut Grid) -> Result<CellValue, EvalError> {
+        let value = self.value.evaluate(env)?;
+        Ok(value.to_cell_value()?)
+    }
 }
 
 impl Expression {
+    /* an error value comes back as Err so it spreads with `?` */
+    pub fn from_cell_value(value: &CellValue) -> Result<Expression, ErrorKind> {
+        match value {
+            CellValue::Empty => Ok(Expression::Empty),
+            CellValue::String(value) => Ok(Expression::String(value.clone())),
+            CellValue::Int(value) => Ok(Expression::Integer(*value)),
+            CellValue::BigInt(value) => Ok(Expression::BigInteger(value.clone())),
+            CellValue::Bool(value) => Ok(Expression::Boolean(*value)),
+            CellValue::Float(value) => Ok(Expression::Float(*value)),
+            CellValue::Error(kind) => Err(kind.clone()),
+        }
+    }
+
+    pub fn to_cell_value(&self) -> Result<CellValue, ErrorKind> {
+        match self {
+            Expression::Empty => Ok(CellValue::Empty),
+            Expression::String(value) => Ok(CellValue::String(value.clone())),
+            Expression::Integer(value) => Ok(CellValue::Int(*value)),
+            Expression::BigInteger(value) => Ok(CellValue::BigInt(value.clone())),
+            Expression::Boolean(value) => Ok(CellValue::Bool(*value)),
+            Expression::Float(value) => Ok(CellValue::Float(*value)),
+            _ => Err(ErrorKind::TypeMismatch(format!(
+                "Formula did not produce a value: {}",
+                self.serialize()
+            ))),
+        }
+    }
+
+    pub fn children(&self) -> Vec<&Expression> {
+        match self {
+            Expression::Empty
+            | Expression::Integer(_)
+            | Expression::BigInteger(_)
+            | Expression::Float(_)
+            | Expression::Boolean(_)
+            | Expression::String(_)
+            | Expression::Error(_) => vec![],
+
+            Expression::Spanned(expr, _)
+            | Expression::Negate(expr)
+            | Expression::LNot(expr)
+            | Expression::BNot(expr)
+            | Expression::ToInt(expr)
+            | Expression::ToFloat(expr) => vec![expr],
+
+            Expression::Add(lhs, rhs)
+            | Expression::Subtract(lhs, rhs)
+            | Expression::Multiply(lhs, rhs)
+            | Expression::Divide(lhs, rhs)
+            | Expression::Modulo(lhs, rhs)
+            | Expression::Exp(lhs, rhs)
+            | Expression::LAnd(lhs, rhs)
+            | Expression::LOr(lhs, rhs)
+            | Expression::CellLValue(lhs, rhs)
+            | Expression::CellRValue(lhs, rhs, _)
+            | Expression::Range(lhs, rhs)
+            | Expression::BAnd(lhs, rhs)
+            | Expression::BOr(lhs, rhs)
+            | Expression::Xor(lhs, rhs)
+            | Expression::LeftShift(lhs, rhs)
+            | Expression::RightShift(lhs, rhs)
+            | Expression::Equals(lhs, rhs)
+            | Expression::NotEquals(lhs, rhs)
+            | Expression::EqualsIgnoreCase(lhs, rhs)
+            | Expression::Concat(lhs, rhs)
+            | Expression::LessThan(lhs, rhs)
+            | Expression::LessThanEq(lhs, rhs)
+            | Expression::GreaterThan(lhs, rhs)
+            | Expression::GreaterThanEq(lhs, rhs) => vec![lhs, rhs],
+
+            Expression::Max(expressions)
+            | Expression::Min(expressions)
+            | Expression::Mean(expressions)
+            | Expression::Sum(expressions)
+            | Expression::Call(_, expressions) => expressions.iter().collect(),
+
+            Expression::Array(range) => range.cells.iter().map(|(_, value)| value).collect(),
+        }
+    }
+
+    /* this node and every node below it, parents first */
+    pub fn visit<'a>(&'a self, visitor: &mut dyn FnMut(&'a Expression)) {
+        visitor(self);
+        for child in self.children() {
+            child.visit(visitor);
+        }
+    }
+
+    pub fn children_mut(&mut self) -> Vec<&mut Expression> {
+        match self {
+            Expression::Empty
+            | Expression::Integer(_)
+            | Expression::BigInteger(_)
+            | Expression::Float(_)
+            | Expression::Boolean(_)
+            | Expression::String(_)
+            | Expression::Error(_) => vec![],
+
+            Expression::Spanned(expr, _)
+            | Expression::Negate(expr)
+            | Expression::LNot(expr)
+            | Expression::BNot(expr)
+            | Expression::ToInt(expr)
+            | Expression::ToFloat(expr) => vec![expr],
+
+            Expression::Add(lhs, rhs)
+            | Expression::Subtract(lhs, rhs)
+            | Expression::Multiply(lhs, rhs)
+            | Expression::Divide(lhs, rhs)
+            | Expression::Modulo(lhs, rhs)
+            | Expression::Exp(lhs, rhs)
+            | Expression::LAnd(lhs, rhs)
+            | Expression::LOr(lhs, rhs)
+            | Expression::CellLValue(lhs, rhs)
+            | Expression::CellRValue(lhs, rhs, _)
+            | Expression::Range(lhs, rhs)
+            | Expression::BAnd(lhs, rhs)
+            | Expression::BOr(lhs, rhs)
+            | Expression::Xor(lhs, rhs)
+            | Expression::LeftShift(lhs, rhs)
+            | Expression::RightShift(lhs, rhs)
+            | Expression::Equals(lhs, rhs)
+            | Expression::NotEquals(lhs, rhs)
+            | Expression::EqualsIgnoreCase(lhs, rhs)
+            | Expression::Concat(lhs, rhs)
+            | Expression::LessThan(lhs, rhs)
+            | Expression::LessThanEq(lhs, rhs)
+            | Expression::GreaterThan(lhs, rhs)
+            | Expression::GreaterThanEq(lhs, rhs) => vec![lhs, rhs],
+
+            Expression::Max(expressions)
+            | Expression::Min(expressions)
+            | Expression::Mean(expressions)
+            | Expression::Sum(expressions)
+            | Expression::Call(_, expressions) => expressions.iter_mut().collect(),
+
+            Expression::Array(range) => range.cells.iter_mut().map(|(_, value)| value).collect(),
+        }
+    }
+
+    /* like visit, a node replaced by the visitor has its new children walked */
+    pub fn visit_mut(&mut self, visitor: &mut dyn FnMut(&mut Expression)) {
+        visitor(self);
+        for child in self.children_mut() {
+            child.visit_mut(visitor);
+        }
+    }
+
+    /*
+     * moves relative references by (rows, cols), what copying the formula that
+     * far does to it. a reference or range pushed off the sheet becomes #REF!
+     */
+    pub fn shift(&mut self, rows: i64, cols: i64) {
+        self.visit_mut(&mut |expr| {
+            let moved = match expr {
+                Expression::Range(start, end) => {
+                    match (start.shifted(rows, cols), end.shifted(rows, cols)) {
+                        (Some(_), Some(_)) => return,
+                        _ => None,
+                    }
+                }
+                Expression::CellRValue(..) => expr.shifted(rows, cols),
+                _ => return,
+            };
+            *expr = moved.unwrap_or_else(Expression::bad_reference);
+        });
+    }
+
+    /* a copy of a CellRValue moved along its relative axes, None off the sheet */
+    fn shifted(&self, rows: i64, cols: i64) -> Option<Expression> {
+        fn axis(index: &Expression, by: i64, absolute: bool) -> Option<Expression> {
+            match index.unspanned() {
+                Expression::Integer(index) if !absolute => index
+                    .checked_add(by)
+                    .filter(|index| *index >= 0)
+                    .map(Expression::Integer),
+                _ => Some(index.clone()),
+            }
+        }
+
+        match self.unspanned() {
+            Expression::CellRValue(col, row, anchor) => Some(Expression::CellRValue(
+                Box::new(axis(col, cols, anchor.col_absolute)?),
+                Box::new(axis(row, rows, anchor.row_absolute)?),
+                *anchor,
+            )),
+            _ => Some(self.clone()),
+        }
+    }
+
+    /*
+     * follows a row or column insert or delete, literal references keep
+     * pointing at the same data. ones to deleted cells become #REF!, a range
+     * only once all of it is gone.
+     */
+    pub fn rewrite(&mut self, edit: SheetEdit) {
+        match self {
+            Expression::Range(start, end) => {
+                if let (Some(first), Some(last)) =
+                    (start.literal_position(), end.literal_position())
+                {
+                    match edit.range(first, last) {
+                        Some((first, last)) => {
+                            start.relocate(first);
+                            end.relocate(last);
+                        }
+                        None => *self = Expression::bad_reference(),
+                    }
+                    return;
+                }
+            }
+            Expression::CellRValue(..) => {
+                if let Some(position) = self.literal_position() {
+                    match edit.cell(position) {
+                        Some(position) => self.relocate(position),
+                        None => *self = Expression::bad_reference(),
+                    }
+                    return;
+                }
+            }
+            _ => {}
+        }
+        for child in self.children_mut() {
+            child.rewrite(edit);
+        }
+    }
+
+    /* points a CellRValue at (row, col), anchors are kept */
+    fn relocate(&mut self, (row, col): (usize, usize)) {
+        match self {
+            Expression::Spanned(expr, _) => expr.relocate((row, col)),
+            Expression::CellRValue(c, r, _) => {
+                **c = Expression::Integer(col as i64);
+                **r = Expression::Integer(row as i64);
+            }
+            _ => {}
+        }
+    }
+
+    fn bad_reference() -> Expression {
+        Expression::Error(ErrorKind::BadReference(
+            "Invalid cell reference".to_string(),
+        ))
+    }
+
+    pub fn unspanned(&self) -> &Expression {
+        match self {
+            Expression::Spanned(expr, _) => expr.unspanned(),
+            _ => self,
+        }
+    }
+
+    /* (row, col) of a CellRValue with literal coordinates */
+    pub fn literal_position(&self) -> Option<(usize, usize)> {
+        match self.unspanned() {
+            Expression::CellRValue(col, row, _) => match (col.unspanned(), row.unspanned()) {
+                (Expression::Integer(col), Expression::Integer(row)) if *col >= 0 && *row >= 0 => {
+                    Some((*row as usize, *col as usize))
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /* cells read through a CellRValue, as (row, col) like the Grid */
+    pub fn references(&self) -> Vec<(usize, usize)> {
+        let mut found = Vec::new();
+        self.visit(&mut |expr| {
+            if let Expression::CellRValue(..) = expr {
+                found.extend(expr.literal_position());
+            }
+        });
+        found
+    }
+
+    /* rectangles read through a Range with literal corners, never expanded */
+    pub fn range_references(&self) -> Vec<((usize, usize), (usize, usize))> {
+        let mut found = Vec::new();
+        self.visit(&mut |expr| {
+            if let Expression::Range(start, end) = expr {
+                if let (Some(start), Some(end)) = (start.literal_position(), end.literal_position())
+                {
+                    found.push(normalize(start, end));
+                }
+            }
+        });
+        found
+    }
+
+    /*
+     * references, ranges and the #REF! a rewrite left in their place, with the
+     * span of source they were parsed from. lets a rewrite touch up only them.
+     */
+    pub fn spanned_references(&self) -> Vec<(Span, &Expression)> {
+        let mut found = Vec::new();
+        self.visit(&mut |expr| {
+            if let Expression::Spanned(inner, span) = expr {
+                if let Expression::CellRValue(..)
+                | Expression::Range(..)
+                | Expression::Error(ErrorKind::BadReference(_)) = inner.unspanned()
+                {
+                    found.push((*span, inner.unspanned()));
+                }
+            }
+        });
+        found
+    }
+
+    /* references with computed coordinates, nothing but evaluation knows what they read */
+    pub fn has_computed_references(&self) -> bool {
+        let mut found = false;
+        self.visit(&mut |expr| {
+            if let Expression::CellRValue(..) = expr {
+                found |= expr.literal_position().is_none();
+            }
+        });
+        found
+    }
+
+    /* names of every function called */
+    pub fn calls(&self) -> Vec<&str> {
+        let mut found = Vec::new();
+        self.visit(&mut |expr| {
+            if let Expression::Call(name, _) = expr {
+                found.push(name.as_str());
+            }
+        });
+        found
+    }
+
     pub fn serialize(&self) -> String {
+        self.serialize_with(Notation::Index)
+    }
+
+    /* cell references with literal coordinates are written in the given notation */
+    pub fn serialize_with(&self, notation: Notation) -> String {
         match self {
+            Expression::Empty => String::new(),
             Expression::Integer(value) => value.to_string(),
-            Expression::Float(value) => value.to_string(),
+            Expression::BigInteger(value) => value.to_string(),
+            /* 2.0 not 2, 1e-9 not 0.000000001, so it reads back as the same float */
+            Expression::Float(value) => format!("{:?}", value),
             Expression::Boolean(value) => value.to_string(),
-            Expression::String(value) => value.to_string(),
+            Expression::String(value) => text::quote(value),
+            Expression::Error(kind) => kind.code().to_string(),
 
-            Expression::Add(lhs, rhs) => format!("({} + {})", lhs.serialize(), rhs.serialize()),
+            Expression::Add(lhs, rhs) => format!(
+                "({} + {})",
+                lhs.serialize_with(notation),
+                rhs.serialize_with(notation)
+            ),
             Expression::Subtract(lhs, rhs) => {
-                format!("({} - {})", lhs.serialize(), rhs.serialize())
+                format!(
+                    "({} - {})",
+                    lhs.serialize_with(notation),
+                    rhs.serialize_with(notation)
+                )
             }
             Expression::Multiply(lhs, rhs) => {
-                format!("({} * {})", lhs.serialize(), rhs.serialize())
+                format!(
+                    "({} * {})",
+                    lhs.serialize_with(notation),
+                    rhs.serialize_with(notation)
+                )
             }
-            Expression::Divide(lhs, rhs) => format!("({} / {})", lhs.serialize(), rhs.serialize()),
-            Expression::Modulo(lhs, rhs) => format!("({} % {})", lhs.serialize(), rhs.serialize()),
-            Expression::Exp(lhs, rhs) => format!("({} ^ {})", lhs.serialize(), rhs.serialize()),
-            Expression::Negate(expr) => format!("-{}", expr.serialize()),
+            Expression::Divide(lhs, rhs) => format!(
+                "({} / {})",
+                lhs.serialize_with(notation),
+                rhs.serialize_with(notation)
+            ),
+            Expression::Modulo(lhs, rhs) => format!(
+                "({} % {})",
+                lhs.serialize_with(notation),
+                rhs.serialize_with(notation)
+            ),
+            Expression::Exp(lhs, rhs) => format!(
+                "({} ** {})",
+                lhs.serialize_with(notation),
+                rhs.serialize_with(notation)
+            ),
+            /* bracketed, -x ** 2 would read back as -(x ** 2) */
+            Expression::Negate(expr) => format!("(-{})", expr.serialize_with(notation)),
+            Expression::Concat(lhs, rhs) => format!(
+                "({} ++ {})",
+                lhs.serialize_with(notation),
+                rhs.serialize_with(notation)
+            ),
 
-            Expression::LAnd(lhs, rhs) => format!("({} && {})", lhs.serialize(), rhs.serialize()),
-            Expression::LOr(lhs, rhs) => format!("({} || {})", lhs.serialize(), rhs.serialize()),
-            Expression::LNot(expr) => format!("!{}", expr.serialize()),
+            Expression::LAnd(lhs, rhs) => format!(
+                "({} && {})",
+                lhs.serialize_with(notation),
+                rhs.serialize_with(notation)
+            ),
+            Expression::LOr(lhs, rhs) => format!(
+                "({} || {})",
+                lhs.serialize_with(notation),
+                rhs.serialize_with(notation)
+            ),
+            Expression::LNot(expr) => format!("(!{})", expr.serialize_with(notation)),
 
             Expression::CellLValue(col, row) => {
-                format!("([{}, {}])", col.serialize(), row.serialize())
+                format!(
+                    "([{}, {}])",
+                    col.serialize_with(notation),
+                    row.serialize_with(notation)
+                )
             }
-            Expression::CellRValue(col, row) => {
-                format!("(#[{}, {}])", col.serialize(), row.serialize())
+            /* index notation has no anchors, relative references come back absolute */
+            Expression::CellRValue(col, row, anchor) => match (notation, self.literal_position()) {
+                (Notation::A1, Some((row, col))) => A1::anchored(row, col, *anchor).to_string(),
+                _ => format!(
+                    "(#[{}, {}])",
+                    col.serialize_with(notation),
+                    row.serialize_with(notation)
+                ),
+            },
+            Expression::Range(start, end) => match (start.unspanned(), end.unspanned()) {
+                _ if notation == Notation::A1
+                    && start.literal_position().is_some()
+                    && end.literal_position().is_some() =>
+                {
+                    format!(
+                        "{}:{}",
+                        start.serialize_with(notation),
+                        end.serialize_with(notation)
+                    )
+                }
+                (Expression::CellRValue(c1, r1, _), Expression::CellRValue(c2, r2, _)) => format!(
+                    "#[{}, {}]:#[{}, {}]",
+                    c1.serialize_with(notation),
+                    r1.serialize_with(notation),
+                    c2.serialize_with(notation),
+                    r2.serialize_with(notation)
+                ),
+                _ => format!(
+                    "{}:{}",
+                    start.serialize_with(notation),
+                    end.serialize_with(notation)
+                ),
+            },
+            /* {B1: 2, A3: 5}, blank cells aren't part of the value */
+            Expression::Array(range) => {
+                let serialized: Vec<String> = range
+                    .cells
+                    .iter()
+                    .map(|&((row, col), ref value)| {
+                        let name = match notation {
+                            Notation::A1 => A1::relative(row, col).to_string(),
+                            Notation::Index => format!("#[{}, {}]", col, row),
+                        };
+                        format!("{}: {}", name, value.serialize_with(notation))
+                    })
+                    .collect();
+                format!("{{{}}}", serialized.join(", "))
             }
 
-            Expression::BAnd(lhs, rhs) => format!("({} & {})", lhs.serialize(), rhs.serialize()),
-            Expression::BOr(lhs, rhs) => format!("({} | {})", lhs.serialize(), rhs.serialize()),
-            Expression::Xor(lhs, rhs) => format!("({} ^ {})", lhs.serialize(), rhs.serialize()),
-            Expression::BNot(expr) => format!("(~{})", expr.serialize()),
+            Expression::BAnd(lhs, rhs) => format!(
+                "({} & {})",
+                lhs.serialize_with(notation),
+                rhs.serialize_with(notation)
+            ),
+            Expression::BOr(lhs, rhs) => format!(
+                "({} | {})",
+                lhs.serialize_with(notation),
+                rhs.serialize_with(notation)
+            ),
+            Expression::Xor(lhs, rhs) => format!(
+                "({} ^ {})",
+                lhs.serialize_with(notation),
+                rhs.serialize_with(notation)
+            ),
+            Expression::BNot(expr) => format!("(~{})", expr.serialize_with(notation)),
             Expression::LeftShift(lhs, rhs) => {
-                format!("({} << {})", lhs.serialize(), rhs.serialize())
+                format!(
+                    "({} << {})",
+                    lhs.serialize_with(notation),
+                    rhs.serialize_with(notation)
+                )
             }
             Expression::RightShift(lhs, rhs) => {
-                format!("({} >> {})", lhs.serialize(), rhs.serialize())
+                format!(
+                    "({} >> {})",
+                    lhs.serialize_with(notation),
+                    rhs.serialize_with(notation)
+                )
             }
 
-            Expression::Equals(lhs, rhs) => format!("({} == {})", lhs.serialize(), rhs.serialize()),
+            Expression::Equals(lhs, rhs) => format!(
+                "({} == {})",
+                lhs.serialize_with(notation),
+                rhs.serialize_with(notation)
+            ),
             Expression::NotEquals(lhs, rhs) => {
-                format!("({} != {})", lhs.serialize(), rhs.serialize())
+                format!(
+                    "({} != {})",
+                    lhs.serialize_with(notation),
+                    rhs.serialize_with(notation)
+                )
             }
+            Expression::EqualsIgnoreCase(lhs, rhs) => format!(
+                "({} ~= {})",
+                lhs.serialize_with(notation),
+                rhs.serialize_with(notation)
+            ),
             Expression::LessThan(lhs, rhs) => {
-                format!("({} < {})", lhs.serialize(), rhs.serialize())
+                format!(
+                    "({} < {})",
+                    lhs.serialize_with(notation),
+                    rhs.serialize_with(notation)
+                )
             }
             Expression::LessThanEq(lhs, rhs) => {
-                format!("({} <= {})", lhs.serialize(), rhs.serialize())
+                format!(
+                    "({} <= {})",
+                    lhs.serialize_with(notation),
+                    rhs.serialize_with(notation)
+                )
             }
             Expression::GreaterThan(lhs, rhs) => {
-                format!("({} > {})", lhs.serialize(), rhs.serialize())
+                format!(
+                    "({} > {})",
+                    lhs.serialize_with(notation),
+                    rhs.serialize_with(notation)
+                )
             }
             Expression::GreaterThanEq(lhs, rhs) => {
-                format!("({} >= {})", lhs.serialize(), rhs.serialize())
+                format!(
+                    "({} >= {})",
+                    lhs.serialize_with(notation),
+                    rhs.serialize_with(notation)
+                )
             }
 
-            Expression::FTI(expr) => format!("(int({}))", expr.serialize()),
-            Expression::ITF(expr) => format!("(float({}))", expr.serialize()),
+            Expression::ToInt(expr) => format!("(int({}))", expr.serialize_with(notation)),
+            Expression::ToFloat(expr) => format!("(float({}))", expr.serialize_with(notation)),
 
             Expression::Max(expressions) => {
-                let serialized: Vec<String> = expressions.iter().map(|e| e.serialize()).collect();
+                let serialized: Vec<String> = expressions
+                    .iter()
+                    .map(|e| e.serialize_with(notation))
+                    .collect();
                 format!("max({})", serialized.join(", "))
             }
             Expression::Min(expressions) => {
-                let serialized: Vec<String> = expressions.iter().map(|e| e.serialize()).collect();
+                let serialized: Vec<String> = expressions
+                    .iter()
+                    .map(|e| e.serialize_with(notation))
+                    .collect();
                 format!("min({})", serialized.join(", "))
             }
             Expression::Mean(expressions) => {
-                let serialized: Vec<String> = expressions.iter().map(|e| e.serialize()).collect();
+                let serialized: Vec<String> = expressions
+                    .iter()
+                    .map(|e| e.serialize_with(notation))
+                    .collect();
                 format!("avg({})", serialized.join(", "))
             }
             Expression::Sum(expressions) => {
-                let serialized: Vec<String> = expressions.iter().map(|e| e.serialize()).collect();
+                let serialized: Vec<String> = expressions
+                    .iter()
+                    .map(|e| e.serialize_with(notation))
+                    .collect();
                 format!("sum({})", serialized.join(", "))
             }
+
+            Expression::Call(name, args) => {
+                let serialized: Vec<String> =
+                    args.iter().map(|e| e.serialize_with(notation)).collect();
+                format!("{}({})", name, serialized.join(", "))
+            }
+
+            Expression::Spanned(expr, _) => expr.serialize_with(notation),
+        }
+    }
+
+    /* blank cells count as zero when used as an operand */
+    fn evaluate_operand(&self, env: &mut Grid) -> Result<Expression, EvalError> {
+        match self.evaluate(env)? {
+            Expression::Empty => Ok(Expression::Integer(0)),
+            value => Ok(value),
+        }
+    }
+
+    /* (row, col) from evaluated coordinates */
+    fn cell_position(col: &Expression, row: &Expression) -> Result<(usize, usize), EvalError> {
+        fn index(value: &Expression, axis: &str) -> Result<usize, EvalError> {
+            match value.unspanned() {
+                Expression::Integer(index) => usize::try_from(*index).map_err(|_| {
+                    ErrorKind::BadReference(format!("{} index {} is off the sheet", axis, index))
+                        .into()
+                }),
+                _ => Err(mismatch(&format!("{} index must be an integer", axis))),
+            }
+        }
+
+        let col = index(col, "Column")?;
+        let row = index(row, "Row")?;
+        Ok((row, col))
+    }
+
+    /* (row, col) a CellRValue points at, #[#[0, 0], 2] reads its column from A1 */
+    fn position(&self, env: &mut Grid) -> Result<(usize, usize), EvalError> {
+        match self {
+            Expression::Spanned(expr, span) => expr.position(env).map_err(|e| e.or_span(*span)),
+            Expression::CellRValue(col, row, _) => {
+                let col = col.evaluate_operand(env)?;
+                let row = row.evaluate_operand(env)?;
+                Expression::cell_position(&col, &row)
+            }
+            _ => Err(mismatch("Range bounds must be cell references")),
+        }
+    }
+
+    /* numbers after promotion, strings by code point. a NaN is unordered so only != holds for it */
+    fn compare(
+        lhs: &Expression,
+        rhs: &Expression,
+        what: &str,
+        test: impl Fn(Option<Ordering>) -> bool,
+    ) -> Result<Expression, EvalError> {
+        let (lhs, rhs) = (&*lhs.unblank(rhs), &*rhs.unblank(lhs));
+        match (lhs, rhs) {
+            (Expression::String(l), Expression::String(r)) => {
+                Ok(Expression::Boolean(test(Some(l.cmp(r)))))
+            }
+            _ => match Operands::of(lhs, rhs) {
+                Some(operands) => Ok(Expression::Boolean(test(number::ordering(operands)))),
+                None => Err(mismatch(&format!("Incompatible types for {}", what))),
+            },
+        }
+    }
+
+    fn equals(lhs: &Expression, rhs: &Expression, what: &str) -> Result<bool, EvalError> {
+        let (lhs, rhs) = (&*lhs.unblank(rhs), &*rhs.unblank(lhs));
+        match (lhs, rhs) {
+            (Expression::Boolean(l), Expression::Boolean(r)) => Ok(l == r),
+            (Expression::String(l), Expression::String(r)) => Ok(l == r),
+            _ => match Operands::of(lhs, rhs) {
+                Some(operands) => Ok(number::ordering(operands).is_some_and(Ordering::is_eq)),
+                None => Err(mismatch(&format!("Incompatible types for {}", what))),
+            },
+        }
+    }
+
+    /* a blank cell compared with text is "", with a boolean false, otherwise 0 */
+    fn unblank(&self, other: &Expression) -> Cow<'_, Expression> {
+        match (self, other) {
+            (Expression::Empty, Expression::String(_)) => {
+                Cow::Owned(Expression::String(String::new()))
+            }
+            (Expression::Empty, Expression::Boolean(_)) => Cow::Owned(Expression::Boolean(false)),
+            (Expression::Empty, _) => Cow::Owned(Expression::Integer(0)),
+            _ => Cow::Borrowed(self),
         }
     }
 
-    pub fn evaluate(&self, env: &mut Grid) -> Result<Expression, String> {
+    fn evaluate_all(
+        expressions: &[Expression],
+        env: &mut Grid,
+    ) -> Result<Vec<Expression>, EvalError> {
+        expressions.iter().map(|e| e.evaluate(env)).collect()
+    }
+
+    pub fn evaluate(&self, env: &mut Grid) -> Result<Expression, EvalError> {
         match self {
-            Expression::Integer(_)
+            Expression::Empty
+            | Expression::Integer(_)
+            | Expression::BigInteger(_)
             | Expression::Float(_)
             | Expression::Boolean(_)
             | Expression::String(_) => Ok(self.clone()),
+            Expression::Error(kind) => Err(kind.clone().into()),
 
             Expression::Add(lhs, rhs) => {
-                let lhs = lhs.evaluate(env)?;
-                let rhs = rhs.evaluate(env)?;
-                match (lhs, rhs) {
-                    (Expression::Integer(l), Expression::Integer(r)) => {
-                        Ok(Expression::Integer(l + r))
-                    }
-                    (Expression::Float(l), Expression::Float(r)) => Ok(Expression::Float(l + r)),
-                    (Expression::Integer(l), Expression::Float(r)) => {
-                        Ok(Expression::Float(l as f64 + r))
-                    }
-                    (Expression::Float(l), Expression::Integer(r)) => {
-                        Ok(Expression::Float(l + r as f64))
-                    }
-                    _ => Err("Incompatible types for addition".to_string()),
-                }
+                let lhs = lhs.evaluate_operand(env)?;
+                let rhs = rhs.evaluate_operand(env)?;
+                number::add(&lhs, &rhs, env.overflow_policy())
+                    .unwrap_or_else(|| Err(mismatch("Incompatible types for addition")))
             }
 
             Expression::Subtract(lhs, rhs) => {
-                let lhs = lhs.evaluate(env)?;
-                let rhs = rhs.evaluate(env)?;
-                match (lhs, rhs) {
-                    (Expression::Integer(l), Expression::Integer(r)) => {
-                        Ok(Expression::Integer(l - r))
-                    }
-                    (Expression::Float(l), Expression::Float(r)) => Ok(Expression::Float(l - r)),
-                    (Expression::Integer(l), Expression::Float(r)) => {
-                        Ok(Expression::Float(l as f64 - r))
-                    }
-                    (Expression::Float(l), Expression::Integer(r)) => {
-                        Ok(Expression::Float(l - r as f64))
-                    }
-                    _ => Err("Incompatible types for subtraction".to_string()),
+                let lhs = lhs.evaluate_operand(env)?;
+                let rhs = rhs.evaluate_operand(env)?;
+                match Operands::of(&lhs, &rhs) {
+                    Some(Operands::Int(l, r)) => match l.checked_sub(r) {
+                        Some(difference) => Ok(Expression::Integer(difference)),
+                        None => number::overflowed(
+                            env.overflow_policy(),
+                            "subtraction",
+                            l as f64 - r as f64,
+                            || Some(BigInt::from(l) - r),
+                        ),
+                    },
+                    Some(Operands::Big(l, r)) => number::big(l - r, "subtraction"),
+                    Some(Operands::Float(l, r)) => Ok(Expression::Float(l - r)),
+                    None => Err(mismatch("Incompatible types for subtraction")),
                 }
             }
 
             Expression::Multiply(lhs, rhs) => {
-                let lhs = lhs.evaluate(env)?;
-                let rhs = rhs.evaluate(env)?;
-                match (lhs, rhs) {
-                    (Expression::Integer(l), Expression::Integer(r)) => {
-                        Ok(Expression::Integer(l * r))
-                    }
-                    (Expression::Float(l), Expression::Float(r)) => Ok(Expression::Float(l * r)),
-                    (Expression::Integer(l), Expression::Float(r)) => {
-                        Ok(Expression::Float(l as f64 * r))
-                    }
-                    (Expression::Float(l), Expression::Integer(r)) => {
-                        Ok(Expression::Float(l * r as f64))
-                    }
-                    _ => Err("Incompatible types for multiplication".to_string()),
+                let lhs = lhs.evaluate_operand(env)?;
+                let rhs = rhs.evaluate_operand(env)?;
+                match Operands::of(&lhs, &rhs) {
+                    Some(Operands::Int(l, r)) => match l.checked_mul(r) {
+                        Some(product) => Ok(Expression::Integer(product)),
+                        None => number::overflowed(
+                            env.overflow_policy(),
+                            "multiplication",
+                            l as f64 * r as f64,
+                            || Some(BigInt::from(l) * r),
+                        ),
+                    },
+                    Some(Operands::Big(l, r)) => number::big(l * r, "multiplication"),
+                    Some(Operands::Float(l, r)) => Ok(Expression::Float(l * r)),
+                    None => match (&lhs, &rhs) {
+                        (Expression::String(text), Expression::Integer(count))
+                        | (Expression::Integer(count), Expression::String(text)) => {
+                            text::repeat(text, *count)
+                        }
+                        _ => Err(mismatch("Incompatible types for multiplication")),
+                    },
                 }
             }
 
-            Expression::Divide(lhs, rhs) => {
+            /* a blank cell joins as nothing rather than 0 */
+            Expression::Concat(lhs, rhs) => {
                 let lhs = lhs.evaluate(env)?;
                 let rhs = rhs.evaluate(env)?;
-                match (lhs, rhs) {
-                    (Expression::Integer(l), Expression::Integer(r)) => {
-                        if r == 0 {
-                            Err("Divide by zero error".to_string())
-                        } else {
-                            Ok(Expression::Integer(l / r))
-                        }
-                    }
-                    (Expression::Float(l), Expression::Float(r)) => {
-                        if r == 0.0 {
-                            Err("Divide by zero error".to_string())
-                        } else {
-                            Ok(Expression::Float(l / r))
-                        }
-                    }
-                    (Expression::Integer(l), Expression::Float(r)) => {
-                        if r == 0.0 {
-                            Err("Divide by zero error".to_string())
-                        } else {
-                            Ok(Expression::Float(l as f64 / r))
-                        }
-                    }
-                    (Expression::Float(l), Expression::Integer(r)) => {
-                        if r == 0 {
-                            Err("Divide by zero error".to_string())
-                        } else {
-                            Ok(Expression::Float(l / r as f64))
-                        }
-                    }
-                    _ => Err("Incompatible types for division".to_string()),
+                text::concat(&lhs, &rhs)
+            }
+
+            Expression::Divide(lhs, rhs) => {
+                let lhs = lhs.evaluate_operand(env)?;
+                let rhs = rhs.evaluate_operand(env)?;
+                match Operands::of(&lhs, &rhs) {
+                    Some(Operands::Int(_, 0)) => Err(ErrorKind::DivideByZero.into()),
+                    /* only i64::MIN / -1 overflows */
+                    Some(Operands::Int(l, r)) => match l.checked_div(r) {
+                        Some(quotient) => Ok(Expression::Integer(quotient)),
+                        None => number::overflowed(
+                            env.overflow_policy(),
+                            "division",
+                            l as f64 / r as f64,
+                            || Some(BigInt::from(l) / r),
+                        ),
+                    },
+                    Some(Operands::Big(_, r)) if r.is_zero() => Err(ErrorKind::DivideByZero.into()),
+                    Some(Operands::Big(l, r)) => number::big(l / r, "division"),
+                    Some(Operands::Float(_, 0.0)) => Err(ErrorKind::DivideByZero.into()),
+                    Some(Operands::Float(l, r)) => Ok(Expression::Float(l / r)),
+                    None => Err(mismatch("Incompatible types for division")),
                 }
             }
 
             Expression::Modulo(lhs, rhs) => {
-                let lhs = lhs.evaluate(env)?;
-                let rhs = rhs.evaluate(env)?;
-                match (lhs, rhs) {
-                    (Expression::Integer(l), Expression::Integer(r)) => {
-                        if r == 0 {
-                            Err("Modulo by zero error".to_string())
-                        } else {
-                            Ok(Expression::Integer(l % r))
-                        }
+                let lhs = lhs.evaluate_operand(env)?;
+                let rhs = rhs.evaluate_operand(env)?;
+                match Operands::of(&lhs, &rhs) {
+                    Some(Operands::Int(_, 0)) => Err(ErrorKind::DivideByZero.into()),
+                    /* i64::MIN % -1 overflows computing a remainder of 0 */
+                    Some(Operands::Int(l, r)) => {
+                        Ok(Expression::Integer(l.checked_rem(r).unwrap_or(0)))
                     }
-                    _ => Err("Modulo operation only valid on integers".to_string()),
+                    Some(Operands::Big(_, r)) if r.is_zero() => Err(ErrorKind::DivideByZero.into()),
+                    Some(Operands::Big(l, r)) => number::big(l % r, "modulo"),
+                    _ => Err(mismatch("Modulo operation only valid on integers")),
                 }
             }
 
             Expression::Exp(lhs, rhs) => {
-                let lhs = lhs.evaluate(env)?;
-                let rhs = rhs.evaluate(env)?;
-                match (lhs, rhs) {
-                    (Expression::Integer(l), Expression::Integer(r)) => {
-                        Ok(Expression::Integer(l.pow(r as u32)))
+                let lhs = lhs.evaluate_operand(env)?;
+                let rhs = rhs.evaluate_operand(env)?;
+                match Operands::of(&lhs, &rhs) {
+                    /* a negative exponent is a fraction, 2 ** -1 is 0.5 */
+                    Some(Operands::Int(0, r)) if r < 0 => Err(ErrorKind::DivideByZero.into()),
+                    Some(Operands::Int(l, r)) if r < 0 => {
+                        Ok(Expression::Float((l as f64).powf(r as f64)))
                     }
-                    (Expression::Float(l), Expression::Float(r)) => {
-                        Ok(Expression::Float(l.powf(r)))
+                    Some(Operands::Int(l, r)) => match number::checked_pow(l, r as u64) {
+                        Some(power) => Ok(Expression::Integer(power)),
+                        None => number::overflowed(
+                            env.overflow_policy(),
+                            "exponentiation",
+                            (l as f64).powf(r as f64),
+                            || number::big_pow(&BigInt::from(l), r as u64),
+                        ),
+                    },
+                    Some(Operands::Big(l, r)) if r.sign() == Sign::Minus && l.is_zero() => {
+                        Err(ErrorKind::DivideByZero.into())
                     }
-                    (Expression::Integer(l), Expression::Float(r)) => {
-                        Ok(Expression::Float((l as f64).powf(r)))
+                    Some(Operands::Big(l, r)) if r.sign() == Sign::Minus => {
+                        let (l, r) = (number::to_float(&Expression::BigInteger(l)), r.to_f64());
+                        Ok(Expression::Float(l.zip(r).map_or(0.0, |(l, r)| l.powf(r))))
                     }
-                    (Expression::Float(l), Expression::Integer(r)) => {
-                        Ok(Expression::Float(l.powf(r as f64)))
+                    Some(Operands::Big(l, r)) => {
+                        let power = r.to_u64().and_then(|r| number::big_pow(&l, r));
+                        number::big(
+                            power.ok_or_else(|| number::overflow("exponentiation"))?,
+                            "exponentiation",
+                        )
                     }
-                    _ => Err("Incompatible types for exponentiation".to_string()),
+                    /* zero to a negative power divides by zero whatever the type */
+                    Some(Operands::Float(0.0, r)) if r < 0.0 => Err(ErrorKind::DivideByZero.into()),
+                    Some(Operands::Float(l, r)) => Ok(Expression::Float(l.powf(r))),
+                    None => Err(mismatch("Incompatible types for exponentiation")),
                 }
             }
 
             Expression::Negate(expr) => {
-                let evaluated_expr = expr.evaluate(env)?;
+                let evaluated_expr = expr.evaluate_operand(env)?;
                 match evaluated_expr {
-                    Expression::Integer(i) => Ok(Expression::Integer(-i)),
+                    Expression::Integer(i) => match i.checked_neg() {
+                        Some(negated) => Ok(Expression::Integer(negated)),
+                        None => number::overflowed(
+                            env.overflow_policy(),
+                            "negation",
+                            -(i as f64),
+                            || Some(-BigInt::from(i)),
+                        ),
+                    },
+                    Expression::BigInteger(b) => number::big(-b, "negation"),
                     Expression::Float(f) => Ok(Expression::Float(-f)),
-                    _ => Err("Negate operation only valid on numeric types".to_string()),
+                    _ => Err(mismatch("Negate operation only valid on numeric types")),
                 }
             }
 
             Expression::LAnd(lhs, rhs) => {
-                let lhs = lhs.evaluate(env)?;
-                let rhs = rhs.evaluate(env)?;
-                match (lhs, rhs) {
-                    (Expression::Boolean(l), Expression::Boolean(r)) => {
-                        Ok(Expression::Boolean(l && r))
-                    }
-                    _ => Err("Logical AND only valid on boolean values".to_string()),
+                /* the right side only runs when the left one leaves the result open */
+                match lhs.evaluate(env)? {
+                    Expression::Boolean(false) => Ok(Expression::Boolean(false)),
+                    Expression::Boolean(true) => match rhs.evaluate(env)? {
+                        Expression::Boolean(r) => Ok(Expression::Boolean(r)),
+                        _ => Err(mismatch("Logical AND only valid on boolean values")),
+                    },
+                    _ => Err(mismatch("Logical AND only valid on boolean values")),
                 }
             }
 
-            Expression::LOr(lhs, rhs) => {
-                let lhs = lhs.evaluate(env)?;
-                let rhs = rhs.evaluate(env)?;
-                match (lhs, rhs) {
-                    (Expression::Boolean(l), Expression::Boolean(r)) => {
-                        Ok(Expression::Boolean(l || r))
-                    }
-                    _ => Err("Logical OR only valid on boolean values".to_string()),
-                }
-            }
+            Expression::LOr(lhs, rhs) => match lhs.evaluate(env)? {
+                Expression::Boolean(true) => Ok(Expression::Boolean(true)),
+                Expression::Boolean(false) => match rhs.evaluate(env)? {
+                    Expression::Boolean(r) => Ok(Expression::Boolean(r)),
+                    _ => Err(mismatch("Logical OR only valid on boolean values")),
+                },
+                _ => Err(mismatch("Logical OR only valid on boolean values")),
+            },
 
             Expression::LNot(expr) => {
                 let expr = expr.evaluate(env)?;
                 match expr {
                     Expression::Boolean(b) => Ok(Expression::Boolean(!b)),
-                    _ => Err("Logical NOT only valid on booleans".to_string()),
+                    _ => Err(mismatch("Logical NOT only valid on booleans")),
                 }
             }
 
-            /* these evaluate to ourselves, it's an assignment */
+            /* these evaluate to ourselves, Assignment does the writing */
             Expression::CellLValue(col, row) => Ok(Expression::CellLValue(
                 *Box::new(col.clone()),
                 Box::new(*row.clone()),
             )),
 
-            Expression::CellRValue(col, row) => {
-                let col_index = match **col {
-                    Expression::Integer(val) => val as usize,
-                    _ => return Err("Column index must be an integer".to_string()),
-                };
-                let row_index = match **row {
-                    Expression::Integer(val) => val as usize,
-                    _ => return Err("Row index must be an integer".to_string()),
-                };
-
-                match env.get_cell(row_index, col_index) {
-                    Some(cell) => {
-                        let cell_value = cell.get_value();
-
-                        match cell_value {
-                            CellValue::String(value) => Ok(Expression::String(value.clone())),
-                            CellValue::Int(value) => Ok(Expression::Integer(*value)),
-                            CellValue::Bool(value) => Ok(Expression::Boolean(*value)),
-                            CellValue::Float(value) => Ok(Expression::Float(*value)),
-                        }
-                    }
-                    None => Err(format!(
-                        "Cell at ({}, {}) not found",
-                        col.serialize(),
-                        row.serialize()
-                    )),
-                }
+            Expression::CellRValue(..) => {
+                let (row_index, col_index) = self.position(env)?;
+                env.record_read((row_index, col_index), (row_index, col_index));
+                env.read_cell(row_index, col_index)
+            }
+
+            /* only the occupied cells are read, a range can span the whole sheet */
+            Expression::Range(start, end) => {
+                let (start, end) = normalize(start.position(env)?, end.position(env)?);
+                env.record_read(start, end);
+                let cells = env
+                    .cells_in(start, end)
+                    .map(|(&pos, cell)| Ok((pos, Expression::from_cell_value(cell.get_value())?)))
+                    .collect::<Result<Vec<_>, EvalError>>()?;
+                Ok(Expression::Array(RangeValue { start, end, cells }))
             }
 
+            Expression::Array(_) => Ok(self.clone()),
+
             Expression::BAnd(lhs, rhs) => {
-                let left = lhs.evaluate(env)?;
-                let right = rhs.evaluate(env)?;
+                let left = lhs.evaluate_operand(env)?;
+                let right = rhs.evaluate_operand(env)?;
 
-                match (left, right) {
-                    (Expression::Integer(l), Expression::Integer(r)) => {
-                        Ok(Expression::Integer(l & r))
-                    }
-                    _ => Err("Incompatible types for Bitwise AND".to_string()),
+                match Operands::of(&left, &right) {
+                    Some(Operands::Int(l, r)) => Ok(Expression::Integer(l & r)),
+                    Some(Operands::Big(l, r)) => number::big(l & r, "bitwise AND"),
+                    _ => Err(mismatch("Incompatible types for Bitwise AND")),
                 }
             }
 
             Expression::BOr(lhs, rhs) => {
-                let left = lhs.evaluate(env)?;
-                let right = rhs.evaluate(env)?;
+                let left = lhs.evaluate_operand(env)?;
+                let right = rhs.evaluate_operand(env)?;
 
-                match (left, right) {
-                    (Expression::Integer(l), Expression::Integer(r)) => {
-                        Ok(Expression::Integer(l | r))
-                    }
-                    _ => Err("Incompatible types for Bitwise OR".to_string()),
+                match Operands::of(&left, &right) {
+                    Some(Operands::Int(l, r)) => Ok(Expression::Integer(l | r)),
+                    Some(Operands::Big(l, r)) => number::big(l | r, "bitwise OR"),
+                    _ => Err(mismatch("Incompatible types for Bitwise OR")),
                 }
             }
 
             Expression::Xor(lhs, rhs) => {
-                let left = lhs.evaluate(env)?;
-                let right = rhs.evaluate(env)?;
+                let left = lhs.evaluate_operand(env)?;
+                let right = rhs.evaluate_operand(env)?;
 
-                match (left, right) {
-                    (Expression::Integer(l), Expression::Integer(r)) => {
-                        Ok(Expression::Integer(l ^ r))
-                    }
-                    _ => Err("Incompatible types for Bitwise XOR".to_string()),
+                match Operands::of(&left, &right) {
+                    Some(Operands::Int(l, r)) => Ok(Expression::Integer(l ^ r)),
+                    Some(Operands::Big(l, r)) => number::big(l ^ r, "bitwise XOR"),
+                    _ => Err(mismatch("Incompatible types for Bitwise XOR")),
                 }
             }
 
             Expression::BNot(expr) => {
-                let evaluated = expr.evaluate(env)?;
+                let evaluated = expr.evaluate_operand(env)?;
                 match evaluated {
                     Expression::Integer(i) => Ok(Expression::Integer(!i)),
-                    _ => Err("Incompatible type for Bitwise NOT".to_string()),
+                    /* two's complement like an i64, !x is -x - 1 */
+                    Expression::BigInteger(i) => number::big(!i, "bitwise NOT"),
+                    _ => Err(mismatch("Incompatible type for Bitwise NOT")),
                 }
             }
 
+            /* shifts take a non-negative amount, past the width of an i64 is fine */
             Expression::LeftShift(lhs, rhs) => {
-                let left = lhs.evaluate(env)?;
-                let right = rhs.evaluate(env)?;
+                let left = lhs.evaluate_operand(env)?;
+                let right = rhs.evaluate_operand(env)?;
 
                 match (left, right) {
-                    (Expression::Integer(l), Expression::Integer(r)) => {
+                    (_, Expression::Integer(r)) if r < 0 => Err(negative_shift()),
+                    (_, Expression::BigInteger(r)) if r.sign() == Sign::Minus => {
+                        Err(negative_shift())
+                    }
+                    (
+                        Expression::Integer(0),
+                        Expression::Integer(_) | Expression::BigInteger(_),
+                    ) => Ok(Expression::Integer(0)),
+                    (Expression::Integer(l), Expression::Integer(r))
+                        if r < 64 && (l << r) >> r == l =>
+                    {
                         Ok(Expression::Integer(l << r))
                     }
-                    _ => Err("Incompatible types for Left Shift".to_string()),
+                    (Expression::Integer(l), Expression::Integer(r)) => number::overflowed(
+                        env.overflow_policy(),
+                        "left shift",
+                        l as f64 * 2f64.powf(r as f64),
+                        || number::big_shl(&BigInt::from(l), r as u64),
+                    ),
+                    (Expression::BigInteger(l), Expression::Integer(r)) => {
+                        let shifted = number::big_shl(&l, r as u64);
+                        number::big(
+                            shifted.ok_or_else(|| number::overflow("left shift"))?,
+                            "left shift",
+                        )
+                    }
+                    (
+                        Expression::Integer(_) | Expression::BigInteger(_),
+                        Expression::BigInteger(_),
+                    ) => Err(number::overflow("left shift")),
+                    _ => Err(mismatch("Incompatible types for Left Shift")),
                 }
             }
-
             Expression::RightShift(lhs, rhs) => {
-                let left = lhs.evaluate(env)?;
-                let right = rhs.evaluate(env)?;
+                let left = lhs.evaluate_operand(env)?;
+                let right = rhs.evaluate_operand(env)?;
 
                 match (left, right) {
+                    (_, Expression::Integer(r)) if r < 0 => Err(negative_shift()),
+                    (_, Expression::BigInteger(r)) if r.sign() == Sign::Minus => {
+                        Err(negative_shift())
+                    }
+                    /* from 63 on only the sign is left */
                     (Expression::Integer(l), Expression::Integer(r)) => {
-                        Ok(Expression::Integer(l >> r))
+                        Ok(Expression::Integer(l >> r.min(63)))
+                    }
+                    (Expression::Integer(l), Expression::BigInteger(_)) => {
+                        Ok(Expression::Integer(l >> 63))
+                    }
+                    (Expression::BigInteger(l), Expression::Integer(r)) => {
+                        number::big(l >> usize::try_from(r).unwrap_or(usize::MAX), "right shift")
                     }
-                    _ => Err("Incompatible types for Right Shift".to_string()),
+                    (Expression::BigInteger(l), Expression::BigInteger(_)) => {
+                        Ok(Expression::Integer(if l.sign() == Sign::Minus {
+                            -1
+                        } else {
+                            0
+                        }))
+                    }
+                    _ => Err(mismatch("Incompatible types for Right Shift")),
                 }
             }
 
             Expression::Equals(lhs, rhs) => {
                 let lhs = lhs.evaluate(env)?;
                 let rhs = rhs.evaluate(env)?;
-                match (lhs, rhs) {
-                    (Expression::Integer(l), Expression::Integer(r)) => {
-                        Ok(Expression::Boolean(l == r))
-                    }
-                    (Expression::Float(l), Expression::Float(r)) => Ok(Expression::Boolean(l == r)),
-                    (Expression::Boolean(l), Expression::Boolean(r)) => {
-                        Ok(Expression::Boolean(l == r))
-                    }
-                    (Expression::String(l), Expression::String(r)) => {
-                        Ok(Expression::Boolean(l == r))
-                    }
-                    _ => Err("Incompatible types for equality comparison".to_string()),
-                }
+                Ok(Expression::Boolean(Expression::equals(
+                    &lhs,
+                    &rhs,
+                    "equality comparison",
+                )?))
             }
 
             Expression::NotEquals(lhs, rhs) => {
                 let lhs = lhs.evaluate(env)?;
                 let rhs = rhs.evaluate(env)?;
-                match (lhs, rhs) {
-                    (Expression::Integer(l), Expression::Integer(r)) => {
-                        Ok(Expression::Boolean(l != r))
-                    }
-                    (Expression::Float(l), Expression::Float(r)) => Ok(Expression::Boolean(l != r)),
-                    (Expression::Boolean(l), Expression::Boolean(r)) => {
-                        Ok(Expression::Boolean(l != r))
-                    }
+                Ok(Expression::Boolean(!Expression::equals(
+                    &lhs,
+                    &rhs,
+                    "inequality comparison",
+                )?))
+            }
+
+            Expression::EqualsIgnoreCase(lhs, rhs) => {
+                let lhs = lhs.evaluate(env)?;
+                let rhs = rhs.evaluate(env)?;
+                Ok(Expression::Boolean(match (&lhs, &rhs) {
                     (Expression::String(l), Expression::String(r)) => {
-                        Ok(Expression::Boolean(l != r))
+                        text::equal_ignoring_case(l, r)
                     }
-                    _ => Err("Incompatible types for inequality comparison".to_string()),
-                }
+                    _ => Expression::equals(&lhs, &rhs, "case-insensitive comparison")?,
+                }))
             }
 
             Expression::LessThan(lhs, rhs) => {
                 let lhs = lhs.evaluate(env)?;
                 let rhs = rhs.evaluate(env)?;
-                match (lhs, rhs) {
-                    (Expression::Integer(l), Expression::Integer(r)) => {
-                        Ok(Expression::Boolean(l < r))
-                    }
-                    (Expression::Float(l), Expression::Float(r)) => Ok(Expression::Boolean(l < r)),
-                    _ => Err("Incompatible types for less-than comparison".to_string()),
-                }
+                Expression::compare(&lhs, &rhs, "less-than comparison", |o| {
+                    o.is_some_and(Ordering::is_lt)
+                })
             }
 
             Expression::LessThanEq(lhs, rhs) => {
                 let lhs = lhs.evaluate(env)?;
                 let rhs = rhs.evaluate(env)?;
-                match (lhs, rhs) {
-                    (Expression::Integer(l), Expression::Integer(r)) => {
-                        Ok(Expression::Boolean(l <= r))
-                    }
-                    (Expression::Float(l), Expression::Float(r)) => Ok(Expression::Boolean(l <= r)),
-                    _ => Err("Incompatible types for less-than-or-equal comparison".to_string()),
-                }
+                Expression::compare(&lhs, &rhs, "less-than-or-equal comparison", |o| {
+                    o.is_some_and(Ordering::is_le)
+                })
             }
 
             Expression::GreaterThan(lhs, rhs) => {
                 let lhs = lhs.evaluate(env)?;
                 let rhs = rhs.evaluate(env)?;
-                match (lhs, rhs) {
-                    (Expression::Integer(l), Expression::Integer(r)) => {
-                        Ok(Expression::Boolean(l > r))
-                    }
-                    (Expression::Float(l), Expression::Float(r)) => Ok(Expression::Boolean(l > r)),
-                    _ => Err("Incompatible types for greater-than comparison".to_string()),
-                }
+                Expression::compare(&lhs, &rhs, "greater-than comparison", |o| {
+                    o.is_some_and(Ordering::is_gt)
+                })
             }
 
             Expression::GreaterThanEq(lhs, rhs) => {
                 let lhs = lhs.evaluate(env)?;
                 let rhs = rhs.evaluate(env)?;
-                match (lhs, rhs) {
-                    (Expression::Integer(l), Expression::Integer(r)) => {
-                        Ok(Expression::Boolean(l >= r))
-                    }
-                    (Expression::Float(l), Expression::Float(r)) => Ok(Expression::Boolean(l >= r)),
-                    _ => Err("Incompatible types for greater-than-or-equal comparison".to_string()),
-                }
+                Expression::compare(&lhs, &rhs, "greater-than-or-equal comparison", |o| {
+                    o.is_some_and(Ordering::is_ge)
+                })
             }
 
-            Expression::FTI(expr) => {
-                let evaluated_expr = expr.evaluate(env)?;
-                match evaluated_expr {
-                    Expression::Integer(i) => Ok(Expression::Float(i as f64)),
-                    _ => Err("FTI operation only valid on integers".to_string()),
-                }
-            }
+            Expression::ToInt(expr) => convert::to_int(
+                &expr.evaluate_operand(env)?,
+                Rounding::Truncate,
+                env.overflow_policy(),
+            ),
 
-            Expression::ITF(expr) => {
-                let evaluated_expr = expr.evaluate(env)?;
-                match evaluated_expr {
-                    Expression::Float(f) => Ok(Expression::Integer(f as i64)),
-                    _ => Err("ITF operation only valid on floats".to_string()),
-                }
-            }
+            Expression::ToFloat(expr) => convert::to_float(&expr.evaluate_operand(env)?),
 
             Expression::Max(expressions) => {
-                let evaluated: Result<Vec<Expression>, String> =
-                    expressions.iter().map(|e| e.evaluate(env)).collect();
-                let evaluated = evaluated?;
-                let mut max_value = &evaluated[0];
-                for expr in &evaluated[1..] {
-                    match (max_value, expr) {
-                        (Expression::Integer(l), Expression::Integer(r)) => {
-                            if r > l {
-                                max_value = expr;
-                            }
-                        }
-                        (Expression::Float(l), Expression::Float(r)) => {
-                            if r > l {
-                                max_value = expr;
-                            }
-                        }
-                        _ => return Err("Incompatible types in Max".to_string()),
-                    }
-                }
-                Ok(max_value.clone())
+                functions::max(&Expression::evaluate_all(expressions, env)?)
             }
-
             Expression::Min(expressions) => {
-                let evaluated: Result<Vec<Expression>, String> =
-                    expressions.iter().map(|e| e.evaluate(env)).collect();
-                let evaluated = evaluated?;
-                let mut min_value = &evaluated[0];
-                for expr in &evaluated[1..] {
-                    match (min_value, expr) {
-                        (Expression::Integer(l), Expression::Integer(r)) => {
-                            if r < l {
-                                min_value = expr;
-                            }
-                        }
-                        (Expression::Float(l), Expression::Float(r)) => {
-                            if r < l {
-                                min_value = expr;
-                            }
-                        }
-                        _ => return Err("Incompatible types in Min".to_string()),
-                    }
-                }
-                Ok(min_value.clone())
+                functions::min(&Expression::evaluate_all(expressions, env)?)
             }
-
             Expression::Mean(expressions) => {
-                let evaluated: Result<Vec<Expression>, String> =
-                    expressions.iter().map(|e| e.evaluate(env)).collect();
-                let evaluated = evaluated?;
-                let sum = evaluated.iter().try_fold(0.0, |acc, e| match e {
-                    Expression::Integer(i) => Ok(acc + *i as f64),
-                    Expression::Float(f) => Ok(acc + *f),
-                    _ => Err("Incompatible types in Mean".to_string()),
-                })?;
-                let mean = sum / evaluated.len() as f64;
-                Ok(Expression::Float(mean))
+                functions::mean(&Expression::evaluate_all(expressions, env)?)
             }
-
             Expression::Sum(expressions) => {
-                let evaluated: Result<Vec<Expression>, String> =
-                    expressions.iter().map(|e| e.evaluate(env)).collect();
-                let evaluated = evaluated?;
-                let sum = evaluated.iter().try_fold(0, |acc, e| match e {
-                    Expression::Integer(i) => Ok(acc + *i),
-                    Expression::Float(f) => Ok(acc + *f as i64),
-                    _ => Err("Incompatible types in Sum".to_string()),
-                })?;
-                Ok(Expression::Integer(sum))
+                let values = Expression::evaluate_all(expressions, env)?;
+                functions::sum(&values, env.overflow_policy())
+            }
+
+            Expression::Call(name, args) => {
+                let function = env.functions().resolve(name, args.len())?;
+                let args = Expression::evaluate_all(args, env)?;
+                function.call(&args, env.overflow_policy())
             }
+
+            Expression::Spanned(expr, span) => expr.evaluate(env).map_err(|e| e.or_span(*span)),
         }
     }
 }
+
+/* top-left and bottom-right of the rectangle two (row, col) corners span */
+pub(crate) fn normalize(
+    (r1, c1): (usize, usize),
+    (r2, c2): (usize, usize),
+) -> ((usize, usize), (usize, usize)) {
+    ((r1.min(r2), c1.min(c2)), (r1.max(r2), c1.max(c2)))
+}
+
+fn negative_shift() -> EvalError {
+    mismatch("Shift amount must not be negative")
+}
+
+pub(crate) fn mismatch(message: &str) -> EvalError {
+    ErrorKind::TypeMismatch(message.to_string()).into()
+}